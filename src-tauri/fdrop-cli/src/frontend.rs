@@ -0,0 +1,89 @@
+use std::io::Write;
+
+use fdrop_net::{BoxFuture, Frontend, LinkResponse, TransferOffer};
+use flume::{Receiver, Sender};
+use serde_json::Value;
+
+/// Frontend that forwards events to the running command and asks for confirmations on the
+/// terminal
+pub struct CliFrontend {
+    auto_accept: bool,
+    events: Sender<(String, Value)>,
+}
+
+impl CliFrontend {
+    pub fn new(auto_accept: bool) -> (Self, Receiver<(String, Value)>) {
+        let (events, erx) = flume::unbounded();
+        (
+            Self {
+                auto_accept,
+                events,
+            },
+            erx,
+        )
+    }
+}
+
+impl Frontend for CliFrontend {
+    fn emit(&self, event: &str, payload: Value) {
+        let _ = self.events.send((event.to_string(), payload));
+    }
+
+    fn confirm_link_request<'a>(
+        &'a self,
+        their_name: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, LinkResponse> {
+        Box::pin(async move {
+            if self.auto_accept {
+                println!("Accepting link request from '{their_name}'");
+                return LinkResponse::Accepted;
+            }
+            let prompt = format!(
+                "Link request from '{their_name}' with verification code {code}\n\
+                 Accept if the same code is shown on the other device [y: accept, n: reject, b: reject and block, m: codes differ]: "
+            );
+            tokio::task::spawn_blocking(move || {
+                print!("{prompt}");
+                std::io::stdout().flush().ok();
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer).is_err() {
+                    return LinkResponse::Other;
+                }
+                match answer.trim() {
+                    "y" | "Y" => LinkResponse::Accepted,
+                    "b" | "B" => LinkResponse::Blocked,
+                    "m" | "M" => LinkResponse::CodeMismatch,
+                    _ => LinkResponse::Rejected,
+                }
+            })
+            .await
+            .unwrap_or(LinkResponse::Other)
+        })
+    }
+
+    fn confirm_transfer<'a>(&'a self, offer: &'a TransferOffer) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            let kind = if offer.directory { "folder" } else { "file" };
+            if self.auto_accept {
+                println!("Accepting {kind} '{}' from '{}'", offer.name, offer.device);
+                return true;
+            }
+            let prompt = format!(
+                "'{}' wants to send the {kind} '{}' ({} bytes). Accept? [y/N]: ",
+                offer.device, offer.name, offer.size
+            );
+            tokio::task::spawn_blocking(move || {
+                print!("{prompt}");
+                std::io::stdout().flush().ok();
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer).is_err() {
+                    return false;
+                }
+                matches!(answer.trim(), "y" | "Y")
+            })
+            .await
+            .unwrap_or(false)
+        })
+    }
+}
@@ -0,0 +1,729 @@
+mod frontend;
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use fdrop_config::{TrustedDevice, UserConfig};
+use fdrop_net::{ConnectionManager, ConnectionState, Direction, LinkResponse, NetworkContext};
+use flume::Receiver;
+use frontend::CliFrontend;
+use serde_json::Value;
+use tracing_subscriber::{filter::Directive, EnvFilter};
+
+/// Identifier of the desktop app. The CLI uses the same data folder so that both share the
+/// identity and the trusted devices
+const APP_IDENTIFIER: &str = "com.github.amythicdev.fdrop";
+
+#[derive(Parser)]
+#[command(
+    name = "fdrop-cli",
+    about = "Share files and text with nearby FDrop devices"
+)]
+struct Cli {
+    /// Folder where the configuration, identity and trusted devices are stored
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create the configuration and identity of this device
+    Init {
+        /// Name under which this device is shown to others. Defaults to the hostname
+        #[arg(long)]
+        name: Option<String>,
+        /// Folder where received files are saved. Defaults to ~/FDrop
+        #[arg(long)]
+        receive_dir: Option<PathBuf>,
+        /// Port on which `daemon` accepts connections from other devices
+        #[arg(long, default_value_t = fdrop_config::DEFAULT_PORT)]
+        port: u16,
+        /// Accept connections only on this address instead of all interfaces
+        #[arg(long)]
+        bind_address: Option<IpAddr>,
+    },
+    /// Stay online and receive whatever linked devices send
+    Daemon {
+        /// Accept every link request and file transfer without asking
+        #[arg(long)]
+        auto_accept: bool,
+        /// Save received files here instead of the configured folder
+        #[arg(long)]
+        receive_dir: Option<PathBuf>,
+    },
+    /// List the devices found on the network
+    Devices {
+        /// Seconds to spend looking for devices
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+    },
+    /// List the devices that this device has linked with
+    Trusted,
+    /// Set how files offered by a linked device are answered
+    Policy {
+        /// Name or public key of the device
+        device: String,
+        /// Accept transfers from the device without asking
+        #[arg(long)]
+        auto_accept: bool,
+        /// Decline transfers larger than this many bytes without asking
+        #[arg(long)]
+        decline_above: Option<u64>,
+    },
+    /// Limit the rate at which data is sent, to all devices together or to one linked device
+    Limit {
+        /// Bytes per second. The limit is removed when not given
+        rate: Option<u64>,
+        /// Name or public key of a linked device to limit instead of all devices
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Refuse link requests from a device without asking. A linked device is unlinked, without
+    /// telling it
+    Block {
+        /// Name or public key of a linked device, or name of any device with `--name`
+        device: String,
+        /// Block every device using the name instead of a single identity
+        #[arg(long)]
+        name: bool,
+    },
+    /// Accept link requests from a blocked device again
+    Unblock {
+        /// Name or public key of the blocked device
+        device: String,
+    },
+    /// List the blocked devices
+    Blocked,
+    /// Remember a device by its address, for networks where it cannot be discovered. The device
+    /// can then be given as `<host>:<port>` to the other commands
+    AddPeer {
+        host: String,
+        #[arg(long, default_value_t = fdrop_config::DEFAULT_PORT)]
+        port: u16,
+    },
+    /// Link with a device
+    Link {
+        device: String,
+        /// Seconds to wait for the device to be discovered
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// End the link with a device. The device is told so if it can be reached
+    Unlink {
+        device: String,
+        /// Seconds to wait for the device to be discovered
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Send files or directories to a device
+    Send {
+        device: String,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Text to send along with the files
+        #[arg(long)]
+        text: Option<String>,
+        /// Seconds to wait for the device to be discovered
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Send a text message to a device
+    Text {
+        device: String,
+        message: String,
+        /// Seconds to wait for the device to be discovered and to acknowledge the message
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Show what was sent to and received from a device, most recent first
+    History {
+        device: String,
+        /// Number of entries to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// Number of most recent entries to skip
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let envfilter = EnvFilter::builder()
+        .with_regex(false)
+        .with_env_var("FDROP_LOG")
+        .with_default_directive(Directive::from_str("warn").unwrap())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .compact()
+        .with_env_filter(envfilter)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let data_dir = match cli.data_dir {
+        Some(data_dir) => data_dir,
+        None => dirs::data_local_dir()
+            .ok_or("cannot determine the local data folder. Use --data-dir")?
+            .join(APP_IDENTIFIER),
+    };
+
+    match cli.command {
+        Command::Init {
+            name,
+            receive_dir,
+            port,
+            bind_address,
+        } => init(&data_dir, name, receive_dir, port, bind_address),
+        Command::Trusted => {
+            let devices = fdrop_config::read_trusted_devices(&data_dir)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            for device in devices {
+                println!(
+                    "{}\t{}\t{}",
+                    device.name,
+                    device.platform.as_deref().unwrap_or("unknown"),
+                    device.public_key
+                );
+            }
+            Ok(())
+        }
+        Command::Policy {
+            device,
+            auto_accept,
+            decline_above,
+        } => {
+            let device = find_linked_device(&data_dir, &device)?;
+            let policy = fdrop_config::TransferPolicy {
+                auto_accept,
+                decline_above,
+            };
+            fdrop_config::set_transfer_policy(&data_dir, &device.public_key, policy)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            Ok(())
+        }
+        Command::Limit { rate, device } => {
+            match device {
+                Some(device) => {
+                    let device = find_linked_device(&data_dir, &device)?;
+                    fdrop_config::set_upload_limit(&data_dir, &device.public_key, rate)
+                        .map_err(|e| fdrop_common::human_readable_error(&e))?;
+                }
+                None => {
+                    let mut config = fdrop_config::read_config(&data_dir)
+                        .map_err(|e| fdrop_common::human_readable_error(&e))?;
+                    config.upload_limit = rate;
+                    fdrop_config::save_config(&data_dir, &config)
+                        .map_err(|e| fdrop_common::human_readable_error(&e))?;
+                }
+            }
+            Ok(())
+        }
+        Command::Block { device, name } => {
+            let blocked = if name {
+                fdrop_config::BlockedDevice {
+                    name: device,
+                    public_key: None,
+                }
+            } else {
+                let device = find_linked_device(&data_dir, &device)?;
+                fdrop_config::remove_trusted_device(&data_dir, &device.public_key)
+                    .map_err(|e| fdrop_common::human_readable_error(&e))?;
+                fdrop_config::BlockedDevice {
+                    name: device.name,
+                    public_key: Some(device.public_key),
+                }
+            };
+            let mut config = fdrop_config::read_config(&data_dir)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            if !config.blocked.contains(&blocked) {
+                config.blocked.push(blocked);
+                fdrop_config::save_config(&data_dir, &config)
+                    .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            }
+            Ok(())
+        }
+        Command::Unblock { device } => {
+            let mut config = fdrop_config::read_config(&data_dir)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            let count = config.blocked.len();
+            config
+                .blocked
+                .retain(|b| b.name != device && b.public_key.as_ref() != Some(&device));
+            if config.blocked.len() == count {
+                return Err(format!("'{device}' is not blocked"));
+            }
+            fdrop_config::save_config(&data_dir, &config)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            Ok(())
+        }
+        Command::Blocked => {
+            let config = fdrop_config::read_config(&data_dir)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            for device in config.blocked {
+                println!(
+                    "{}\t{}",
+                    device.name,
+                    device.public_key.as_deref().unwrap_or("any identity")
+                );
+            }
+            Ok(())
+        }
+        Command::AddPeer { host, port } => {
+            let mut config = fdrop_config::read_config(&data_dir)
+                .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            let peer = fdrop_config::StaticPeer { host, port };
+            if !config.static_peers.contains(&peer) {
+                config.static_peers.push(peer);
+                fdrop_config::save_config(&data_dir, &config)
+                    .map_err(|e| fdrop_common::human_readable_error(&e))?;
+            }
+            Ok(())
+        }
+        Command::Daemon {
+            auto_accept,
+            receive_dir,
+        } => {
+            let (ctx, events) = start(&data_dir, true, auto_accept, receive_dir).await?;
+            println!("Waiting for devices. Press Ctrl-C to stop");
+            tokio::select! {
+                _ = print_events(events) => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            shutdown(&ctx).await;
+            Ok(())
+        }
+        Command::Devices { wait } => {
+            let (ctx, _events) = start(&data_dir, false, false, None).await?;
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            {
+                let connection_manager = ctx.connection_manager.lock().await;
+                for con in connection_manager.get_connectionss() {
+                    let status = match con.state {
+                        ConnectionState::Linked => "linked",
+                        ConnectionState::Connecting => "connecting",
+                        ConnectionState::Offline => "not linked",
+                    };
+                    println!("{}\t{}", con.name, status);
+                }
+            }
+            shutdown(&ctx).await;
+            Ok(())
+        }
+        Command::Link { device, timeout } => {
+            let (ctx, events) = start(&data_dir, false, false, None).await?;
+            let res = connect(&ctx, &device, timeout, &events).await;
+            shutdown(&ctx).await;
+            res.map(|cname| println!("Linked with '{cname}'"))
+        }
+        Command::Send {
+            device,
+            paths,
+            text,
+            timeout,
+        } => {
+            let (ctx, events) = start(&data_dir, false, false, None).await?;
+            let res = async {
+                let cname = connect(&ctx, &device, timeout, &events).await?;
+                send(&ctx, &cname, paths, text, events).await
+            }
+            .await;
+            shutdown(&ctx).await;
+            res
+        }
+        Command::Unlink { device, timeout } => {
+            let device = find_linked_device(&data_dir, &device)?;
+            let (ctx, events) = start(&data_dir, false, false, None).await?;
+            let res = async {
+                let cname = match connect(&ctx, &device.name, timeout, &events).await {
+                    Ok(cname) => cname,
+                    Err(e) => {
+                        println!("{e}. It still trusts this device");
+                        device.name.clone()
+                    }
+                };
+                fdrop_net::unlink_device(&ctx, &cname).await?;
+                Ok::<(), String>(())
+            }
+            .await;
+            shutdown(&ctx).await;
+            res
+        }
+        Command::Text {
+            device,
+            message,
+            timeout,
+        } => {
+            let (ctx, events) = start(&data_dir, false, false, None).await?;
+            let res = async {
+                let cname = connect(&ctx, &device, timeout, &events).await?;
+                let message_id = fdrop_net::send_text_message(&ctx, &cname, message).await?;
+                wait_for_receipt(message_id, timeout, &events).await
+            }
+            .await;
+            shutdown(&ctx).await;
+            res
+        }
+        Command::History {
+            device,
+            limit,
+            skip,
+        } => {
+            let entries = fdrop_net::read_transfer_history(&data_dir, &device, skip, limit).await?;
+            for entry in entries {
+                let path = entry.path.map(|p| p.display().to_string());
+                println!(
+                    "{}\t{:?}\t{:?}\t{:?}\t{}\t{}",
+                    entry.timestamp,
+                    entry.direction,
+                    entry.kind,
+                    entry.status,
+                    entry.content,
+                    path.unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+fn init(
+    data_dir: &Path,
+    name: Option<String>,
+    receive_dir: Option<PathBuf>,
+    port: u16,
+    bind_address: Option<IpAddr>,
+) -> Result<(), String> {
+    if fdrop_config::config_exists(data_dir) {
+        return Err(format!("FDrop is already set up in {}", data_dir.display()));
+    }
+    let instance_name = match name {
+        Some(name) => name,
+        None => whoami::fallible::hostname().map_err(|e| e.to_string())?,
+    };
+    let fdrop_dir = match receive_dir {
+        Some(dir) => dir,
+        None => dirs::home_dir()
+            .ok_or("cannot determine the home folder. Use --receive-dir")?
+            .join("FDrop"),
+    };
+    let config = UserConfig {
+        user: whoami::realname(),
+        instance_name,
+        fdrop_dir,
+        port,
+        bind_address,
+        static_peers: Vec::new(),
+        upload_limit: None,
+        blocked: Vec::new(),
+    };
+    fdrop_config::save_config(data_dir, &config)
+        .and_then(|_| fdrop_config::generate_keys(data_dir))
+        .map_err(|e| fdrop_common::human_readable_error(&e))?;
+    println!(
+        "Set up '{}'. Received files are saved in {}",
+        config.instance_name,
+        config.fdrop_dir.display()
+    );
+    Ok(())
+}
+
+/// Find a linked device by its name or public key
+fn find_linked_device(data_dir: &Path, device: &str) -> Result<TrustedDevice, String> {
+    fdrop_config::read_trusted_devices(data_dir)
+        .map_err(|e| fdrop_common::human_readable_error(&e))?
+        .into_iter()
+        .find(|d| d.name == device || d.public_key == device)
+        .ok_or_else(|| format!("'{device}' is not a linked device"))
+}
+
+/// Start discovery and accepting connections. Only the daemon accepts connections on the
+/// configured port. The other commands exit once done and let the system pick a port, so that
+/// they also work while the daemon runs
+async fn start(
+    data_dir: &Path,
+    daemon: bool,
+    auto_accept: bool,
+    receive_dir: Option<PathBuf>,
+) -> Result<(NetworkContext, Receiver<(String, Value)>), String> {
+    let mut config = fdrop_config::read_config(data_dir).map_err(|_| {
+        format!(
+            "FDrop is not set up in {}. Run `fdrop-cli init` first",
+            data_dir.display()
+        )
+    })?;
+    if let Some(receive_dir) = receive_dir {
+        config.fdrop_dir = receive_dir;
+    }
+    if !daemon {
+        config.port = 0;
+    }
+    let connection_manager = ConnectionManager::new()?;
+    let (frontend, events) = CliFrontend::new(auto_accept);
+    let ctx = NetworkContext::new(
+        Arc::new(connection_manager),
+        config,
+        data_dir.to_path_buf(),
+        frontend,
+    );
+    fdrop_net::start_networking(&ctx).await?;
+    Ok((ctx, events))
+}
+
+async fn shutdown(ctx: &NetworkContext) {
+    if let Err(e) = ctx.connection_manager.lock().await.shutdown() {
+        eprintln!("failed to stop discovery: {e}");
+    }
+}
+
+/// Wait for `device` to be discovered and link with it. Returns the full name of the device
+async fn connect(
+    ctx: &NetworkContext,
+    device: &str,
+    timeout: u64,
+    events: &Receiver<(String, Value)>,
+) -> Result<String, String> {
+    let cname = tokio::time::timeout(Duration::from_secs(timeout), async {
+        loop {
+            {
+                let connection_manager = ctx.connection_manager.lock().await;
+                // Devices can be given by their full mDNS name or just the instance name
+                let found = connection_manager
+                    .get_connectionss()
+                    .find(|c| c.name == device || c.name.starts_with(&format!("{device}.")));
+                if let Some(con) = found {
+                    return con.name.clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    })
+    .await
+    .map_err(|_| format!("device '{device}' was not found"))?;
+
+    match fdrop_net::link_device(ctx, &cname).await? {
+        LinkResponse::Accepted => Ok(linked_name(cname, events)),
+        LinkResponse::CodeMismatch => Err(format!(
+            "verification codes did not match. The connection to '{device}' may have been tampered with"
+        )),
+        LinkResponse::Rejected | LinkResponse::Other | LinkResponse::Blocked => {
+            Err(format!("'{device}' rejected the link request"))
+        }
+    }
+}
+
+/// Name of the device `cname` after linking with it. Devices added by address are renamed to
+/// their real name, which is reported by removing the old device and discovering the new one
+fn linked_name(cname: String, events: &Receiver<(String, Value)>) -> String {
+    let mut renamed = false;
+    for (event, payload) in events.try_iter() {
+        match event.as_str() {
+            fdrop_net::DEVICE_REMOVED if payload["name"] == cname.as_str() => renamed = true,
+            fdrop_net::DEVICE_DISCOVERED if renamed => {
+                return payload["name"].as_str().unwrap_or_default().to_string();
+            }
+            _ => {}
+        }
+    }
+    cname
+}
+
+/// Wait until the device acknowledges the text message `message_id`
+async fn wait_for_receipt(
+    message_id: u32,
+    timeout: u64,
+    events: &Receiver<(String, Value)>,
+) -> Result<(), String> {
+    let receipt = tokio::time::timeout(Duration::from_secs(timeout), async {
+        while let Ok((event, payload)) = events.recv_async().await {
+            if event != fdrop_net::MESSAGE_RECEIPT || payload["message_id"] != message_id {
+                continue;
+            }
+            match payload["status"].as_str() {
+                Some("delivered") => return Ok(()),
+                Some("failed") => break,
+                _ => {}
+            }
+        }
+        Err("the device did not receive the message".to_string())
+    });
+    receipt
+        .await
+        .map_err(|_| "the device did not acknowledge the message".to_string())?
+}
+
+/// Send `paths` to the linked device `cname` and wait until the device has received all of them
+async fn send(
+    ctx: &NetworkContext,
+    cname: &str,
+    paths: Vec<PathBuf>,
+    text: Option<String>,
+    events: Receiver<(String, Value)>,
+) -> Result<(), String> {
+    let (dirs, files): (Vec<_>, Vec<_>) = paths.into_iter().partition(|p| p.is_dir());
+    let mut expected = files.len() as u64;
+    // Events are handled while sending so that progress is shown as it happens
+    let sending = async {
+        fdrop_net::send_files(ctx, cname, files, text.clone()).await?;
+        for dir in dirs {
+            fdrop_net::send_directory(ctx, cname, &dir, text.clone()).await?;
+        }
+        Ok::<(), String>(())
+    };
+    tokio::pin!(sending);
+
+    let mut sent = false;
+    let mut failed = 0;
+    let mut done = 0;
+    while !sent || done + failed < expected {
+        let (event, payload) = tokio::select! {
+            res = &mut sending, if !sent => {
+                res?;
+                sent = true;
+                continue;
+            }
+            event = events.recv_async() => match event {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                // Let the device know so that it does not keep the partially received files
+                for progress in fdrop_net::active_transfers(ctx) {
+                    if progress.device != cname || progress.direction != Direction::Outgoing {
+                        continue;
+                    }
+                    let transfer_id = progress.transfer_id;
+                    fdrop_net::cancel_transfer(ctx, cname, transfer_id, Direction::Outgoing)
+                        .await
+                        .ok();
+                }
+                return Err("cancelled".to_string());
+            }
+        };
+        match event.as_str() {
+            // Reported once when a directory transfer starts
+            fdrop_net::DIRECTORY_PROGRESS if payload["files_done"] == 0 => {
+                expected += payload["file_count"].as_u64().unwrap_or(0);
+            }
+            fdrop_net::TRANSFER_PROGRESS => {
+                let bytes_done = payload["bytes_done"].as_u64().unwrap_or(0);
+                let total_size = payload["total_size"].as_u64().unwrap_or(0).max(1);
+                let eta = match payload["eta"].as_u64() {
+                    Some(eta) => format!("{eta}s left"),
+                    None => "estimating".to_string(),
+                };
+                eprintln!(
+                    "{}: {}% at {} KB/s, {eta}",
+                    payload["file_name"].as_str().unwrap_or_default(),
+                    bytes_done * 100 / total_size,
+                    payload["throughput"].as_u64().unwrap_or(0) / 1024,
+                );
+            }
+            fdrop_net::TRANSFER_COMPLETE => {
+                done += 1;
+                println!("Sent {}", payload["file_path"].as_str().unwrap_or_default());
+            }
+            fdrop_net::TRANSFER_FAILED => {
+                failed += 1;
+                eprintln!(
+                    "Failed to send a file: {}",
+                    payload["error"].as_str().unwrap_or_default()
+                );
+            }
+            _ => {}
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} file(s) could not be sent"));
+    }
+    Ok(())
+}
+
+/// Print what the daemon receives until the networking core stops
+async fn print_events(events: Receiver<(String, Value)>) {
+    while let Ok((event, payload)) = events.recv_async().await {
+        match event.as_str() {
+            fdrop_net::DEVICE_DISCOVERED => {
+                println!("Found '{}'", payload["name"].as_str().unwrap_or_default());
+            }
+            fdrop_net::DEVICE_REMOVED => {
+                println!("'{}' left", payload["name"].as_str().unwrap_or_default());
+            }
+            fdrop_net::DEVICE_LINKED => {
+                println!(
+                    "Linked with '{}'",
+                    payload["name"].as_str().unwrap_or_default()
+                );
+            }
+            fdrop_net::DEVICE_CONNECTING => {
+                println!(
+                    "Connecting to '{}'",
+                    payload["name"].as_str().unwrap_or_default()
+                );
+            }
+            fdrop_net::DELIVERY => {
+                let device = payload["device"].as_str().unwrap_or_default();
+                match payload["state"].as_str() {
+                    Some("delivered") => println!("Delivered queued item to '{device}'"),
+                    Some("failed") => println!(
+                        "Failed to deliver queued item to '{device}': {}",
+                        payload["error"].as_str().unwrap_or_default()
+                    ),
+                    _ => {}
+                }
+            }
+            fdrop_net::DEVICE_UNLINKED => {
+                println!(
+                    "'{}' ended the link",
+                    payload["name"].as_str().unwrap_or_default()
+                );
+            }
+            fdrop_net::DEVICE_DISCONNECTED => {
+                println!(
+                    "'{}' disconnected",
+                    payload["name"].as_str().unwrap_or_default()
+                );
+            }
+            fdrop_net::TRANSFER => match &payload["display_content"] {
+                Value::String(text) => println!("Message: {text}"),
+                content => {
+                    println!(
+                        "Receiving {}",
+                        content["file_path"].as_str().unwrap_or_default()
+                    );
+                    if let Some(text) = content["assoc_text"].as_str() {
+                        println!("Message: {text}");
+                    }
+                }
+            },
+            fdrop_net::TRANSFER_COMPLETE => {
+                println!(
+                    "Received {}",
+                    payload["file_path"].as_str().unwrap_or_default()
+                );
+            }
+            fdrop_net::TRANSFER_FAILED => {
+                eprintln!(
+                    "Failed to receive a file: {}",
+                    payload["error"].as_str().unwrap_or_default()
+                );
+            }
+            _ => {}
+        }
+    }
+}
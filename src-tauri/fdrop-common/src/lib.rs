@@ -8,7 +8,7 @@ pub fn human_readable_error(start: &dyn std::error::Error) -> String {
 
     let i = 1;
     while let Some(e) = err.source() {
-        s.push_str(&format!("{i}.\t{}", e.to_string()));
+        s.push_str(&format!("{i}.\t{e}"));
         err = e;
     }
     s
@@ -1,6 +1,15 @@
+#[cfg(feature = "tauri")]
 use fdrop_common::human_readable_error;
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "tauri")]
 use tauri::{AppHandle, Manager};
 
 #[derive(thiserror::Error, Debug)]
@@ -27,61 +36,300 @@ pub enum ConfigError {
     DataDirUnresolved,
     #[error("invalid json config")]
     InvalidConfig,
+    #[error("invalid trusted devices file")]
+    InvalidTrustedDevices,
 }
 
-const CONFIGFILE: &'static str = "config.json";
+const CONFIGFILE: &str = "config.json";
+const DEVICESFILE: &str = "devices.json";
+/// Port on which FDrop accepts connections unless configured otherwise
+pub const DEFAULT_PORT: u16 = 10116;
+
+/// Serializes read-modify-write cycles on the trusted devices file
+static DEVICES_LOCK: Mutex<()> = Mutex::new(());
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UserConfig {
     pub user: String,
     pub instance_name: String,
     pub fdrop_dir: PathBuf,
+    /// Port on which connections from other devices are accepted
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Address on which connections from other devices are accepted. When unset, connections are
+    /// accepted on all interfaces
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    /// Devices to connect to by address, for networks where they cannot be discovered
+    #[serde(default)]
+    pub static_peers: Vec<StaticPeer>,
+    /// Maximum rate in bytes per second at which data is sent to all devices together
+    #[serde(default)]
+    pub upload_limit: Option<u64>,
+    /// Devices whose link requests are refused without asking the user
+    #[serde(default)]
+    pub blocked: Vec<BlockedDevice>,
+}
+
+/// A device that is reached by its address instead of being discovered
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StaticPeer {
+    /// Hostname or IP address of the device
+    pub host: String,
+    pub port: u16,
+}
+
+/// A device whose link requests are refused without asking the user
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockedDevice {
+    pub name: String,
+    /// Hex encoded ed25519 identity key of the device. When unset, every device using the name is
+    /// blocked
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+impl BlockedDevice {
+    /// Whether this entry blocks the device with identity `public_key` and instance name `name`
+    pub fn matches(&self, public_key: &str, name: &str) -> bool {
+        match &self.public_key {
+            Some(key) => key == public_key,
+            None => self.name == name,
+        }
+    }
 }
 
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+/// A device that the user has accepted a link with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrustedDevice {
+    /// Hex encoded ed25519 identity key of the device
+    pub public_key: String,
+    pub name: String,
+    pub platform: Option<String>,
+    /// Time when the device was first linked, in seconds since the Unix epoch
+    pub first_seen: u64,
+    /// Time when the device was last linked, in seconds since the Unix epoch
+    pub last_seen: u64,
+    /// How files offered by the device are answered
+    #[serde(default)]
+    pub policy: TransferPolicy,
+    /// Maximum rate in bytes per second at which data is sent to the device
+    #[serde(default)]
+    pub upload_limit: Option<u64>,
+}
+
+/// How file transfers offered by a device are answered. Transfers that neither rule applies to
+/// are left for the user to accept or decline
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TransferPolicy {
+    /// Accept transfers without asking the user
+    #[serde(default)]
+    pub auto_accept: bool,
+    /// Decline transfers larger than this many bytes without asking the user
+    #[serde(default)]
+    pub decline_above: Option<u64>,
+}
+
+#[cfg(feature = "tauri")]
 pub fn data_dir(handle: &AppHandle) -> tauri::Result<PathBuf> {
     handle.path().app_local_data_dir()
 }
 
+#[cfg(feature = "tauri")]
 pub fn read_keys(handle: &AppHandle) -> Result<libp2p_identity::ed25519::Keypair, ConfigError> {
     data_dir(handle)
         .map_err(|_| ConfigError::DataDirUnresolved)
-        .and_then(|f| {
-            let mut identity = [0u8; 64];
-            let mut identity_path = f.clone();
-            identity_path.push("identity");
-            File::open(&identity_path)?
-                .read_exact(&mut identity)
-                .map_err(|e| ConfigError::KeyReadError(e))?;
-            libp2p_identity::ed25519::Keypair::try_from_bytes(&mut identity)
-                .map_err(|_| ConfigError::InvalidIdentityBytes)
-        })
+        .and_then(|d| read_keys_from(&d))
+}
+
+/// Read the identity key pair stored in `data_dir`
+pub fn read_keys_from(data_dir: &Path) -> Result<libp2p_identity::ed25519::Keypair, ConfigError> {
+    let mut identity = [0u8; 64];
+    File::open(data_dir.join("identity"))?
+        .read_exact(&mut identity)
+        .map_err(ConfigError::KeyReadError)?;
+    libp2p_identity::ed25519::Keypair::try_from_bytes(&mut identity)
+        .map_err(|_| ConfigError::InvalidIdentityBytes)
+}
+
+/// Generate a new identity key pair and store it in `data_dir`
+pub fn generate_keys(data_dir: &Path) -> Result<(), ConfigError> {
+    let keypair = libp2p_identity::ed25519::Keypair::generate();
+    File::create(data_dir.join("identity"))
+        .and_then(|mut f| f.write_all(&keypair.to_bytes()))
+        .map_err(ConfigError::KeyWriteError)
 }
 
+#[cfg(feature = "tauri")]
 pub fn get_details_from_config(handle: &AppHandle) -> Result<UserConfig, String> {
-    let configfile = data_dir(&handle)
-        .and_then(|mut d| {
-            d.push(CONFIGFILE);
-            Ok(d)
-        })
-        .map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
-
-    let mut file = File::open(configfile).map_err(|e| {
-        let start = ConfigError::from(e);
-        human_readable_error(&start)
+    let data_dir = data_dir(&handle).map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
+    read_config(&data_dir).map_err(|e| human_readable_error(&e))
+}
+
+/// Read the user configuration stored in `data_dir`
+pub fn read_config(data_dir: &Path) -> Result<UserConfig, ConfigError> {
+    let mut buf = String::with_capacity(256);
+    File::open(data_dir.join(CONFIGFILE))?.read_to_string(&mut buf)?;
+    serde_json::from_str(&buf).map_err(|_| ConfigError::InvalidConfig)
+}
+
+/// Save `config` in `data_dir` and create the FDrop folder if it does not exist
+pub fn save_config(data_dir: &Path, config: &UserConfig) -> Result<(), ConfigError> {
+    std::fs::create_dir_all(data_dir).map_err(|e| ConfigError::DataDirNotCreated {
+        path: data_dir.to_path_buf(),
+        source: e,
     })?;
+    let json_config = serde_json::to_string(config).unwrap();
+    File::create(data_dir.join(CONFIGFILE))?.write_all(json_config.as_bytes())?;
+    std::fs::create_dir_all(&config.fdrop_dir).map_err(|e| ConfigError::FDRopDirNotCreated {
+        path: config.fdrop_dir.clone(),
+        source: e,
+    })
+}
+
+/// Whether FDrop has been set up in `data_dir`
+pub fn config_exists(data_dir: &Path) -> bool {
+    data_dir.join(CONFIGFILE).exists()
+}
 
+fn read_devices_file(path: &Path) -> Result<Vec<TrustedDevice>, ConfigError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
     let mut buf = String::with_capacity(256);
-    let _ = file.read_to_string(&mut buf).map_err(|e| {
-        let start = ConfigError::from(e);
-        human_readable_error(&start)
-    });
+    File::open(path)?.read_to_string(&mut buf)?;
+    serde_json::from_str(&buf).map_err(|_| ConfigError::InvalidTrustedDevices)
+}
 
-    let user_config: UserConfig =
-        serde_json::from_str(&buf).map_err(|_| ConfigError::InvalidConfig.to_string())?;
+fn write_devices_file(path: &Path, devices: &[TrustedDevice]) -> Result<(), ConfigError> {
+    let json = serde_json::to_string(devices).unwrap();
+    // Replaced in one step, so that a crash while writing cannot lose the linked devices
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, json)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
 
-    Ok(user_config)
+/// Get all devices that the user has linked with
+pub fn read_trusted_devices(data_dir: &Path) -> Result<Vec<TrustedDevice>, ConfigError> {
+    let _lock = DEVICES_LOCK.lock().unwrap();
+    read_devices_file(&data_dir.join(DEVICESFILE))
 }
 
+pub fn is_trusted_device(data_dir: &Path, public_key: &str) -> bool {
+    read_trusted_devices(data_dir)
+        .map(|devices| devices.iter().any(|d| d.public_key == public_key))
+        .unwrap_or(false)
+}
+
+/// Record a link with the device having `public_key`. If the device is already known, its name,
+/// platform and the time it was last seen are updated
+pub fn trust_device(
+    data_dir: &Path,
+    public_key: &str,
+    name: &str,
+    platform: Option<String>,
+) -> Result<(), ConfigError> {
+    let _lock = DEVICES_LOCK.lock().unwrap();
+    let path = data_dir.join(DEVICESFILE);
+    let mut devices = read_devices_file(&path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    match devices.iter_mut().find(|d| d.public_key == public_key) {
+        Some(device) => {
+            device.name = name.to_string();
+            device.platform = platform;
+            device.last_seen = now;
+        }
+        None => devices.push(TrustedDevice {
+            public_key: public_key.to_string(),
+            name: name.to_string(),
+            platform,
+            first_seen: now,
+            last_seen: now,
+            policy: TransferPolicy::default(),
+            upload_limit: None,
+        }),
+    }
+    write_devices_file(&path, &devices)
+}
+
+/// Get the trusted device having `public_key`
+pub fn find_trusted_device(data_dir: &Path, public_key: &str) -> Option<TrustedDevice> {
+    read_trusted_devices(data_dir)
+        .ok()?
+        .into_iter()
+        .find(|d| d.public_key == public_key)
+}
+
+/// Get the transfer policy of the device having `public_key`. Devices that are not trusted get
+/// the default policy
+pub fn transfer_policy(data_dir: &Path, public_key: &str) -> TransferPolicy {
+    find_trusted_device(data_dir, public_key)
+        .map(|d| d.policy)
+        .unwrap_or_default()
+}
+
+/// Apply `update` to the trusted device having `public_key`. Returns `false` if no such device is
+/// trusted
+fn update_trusted_device(
+    data_dir: &Path,
+    public_key: &str,
+    update: impl FnOnce(&mut TrustedDevice),
+) -> Result<bool, ConfigError> {
+    let _lock = DEVICES_LOCK.lock().unwrap();
+    let path = data_dir.join(DEVICESFILE);
+    let mut devices = read_devices_file(&path)?;
+    let Some(device) = devices.iter_mut().find(|d| d.public_key == public_key) else {
+        return Ok(false);
+    };
+    update(device);
+    write_devices_file(&path, &devices)?;
+    Ok(true)
+}
+
+/// Change the transfer policy of the device having `public_key`. Returns `false` if no such
+/// device is trusted
+pub fn set_transfer_policy(
+    data_dir: &Path,
+    public_key: &str,
+    policy: TransferPolicy,
+) -> Result<bool, ConfigError> {
+    update_trusted_device(data_dir, public_key, |d| d.policy = policy)
+}
+
+/// Change the upload rate limit of the device having `public_key`. Returns `false` if no such
+/// device is trusted
+pub fn set_upload_limit(
+    data_dir: &Path,
+    public_key: &str,
+    upload_limit: Option<u64>,
+) -> Result<bool, ConfigError> {
+    update_trusted_device(data_dir, public_key, |d| d.upload_limit = upload_limit)
+}
+
+/// Forget the device having `public_key`. Returns `false` if no such device was trusted
+pub fn remove_trusted_device(data_dir: &Path, public_key: &str) -> Result<bool, ConfigError> {
+    let _lock = DEVICES_LOCK.lock().unwrap();
+    let path = data_dir.join(DEVICESFILE);
+    let mut devices = read_devices_file(&path)?;
+    let count = devices.len();
+    devices.retain(|d| d.public_key != public_key);
+    if devices.len() == count {
+        return Ok(false);
+    }
+    write_devices_file(&path, &devices)?;
+    Ok(true)
+}
+
+#[cfg(feature = "tauri")]
 pub async fn check_first_launch(handle: &AppHandle) -> bool {
     let configfile = handle.path().app_local_data_dir().and_then(|mut d| {
         d.push(CONFIGFILE);
@@ -91,10 +339,11 @@ pub async fn check_first_launch(handle: &AppHandle) -> bool {
     !(configfile.is_ok() && configfile.unwrap().exists())
 }
 
+#[cfg(feature = "tauri")]
 pub mod commands {
-    use super::{data_dir, ConfigError, UserConfig, CONFIGFILE};
+    use super::{data_dir, ConfigError, TransferPolicy, TrustedDevice, UserConfig, CONFIGFILE};
     use fdrop_common::human_readable_error;
-    use std::{fs::File, io::Write, path::PathBuf, sync::Mutex};
+    use std::{fs::File, io::Write, path::PathBuf};
     use tauri::{AppHandle, Manager};
 
     #[tauri::command]
@@ -110,7 +359,6 @@ pub mod commands {
                 Ok(d)
             })
             .map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
-        handle.manage(Mutex::new(config.clone()));
         let json_config = serde_json::to_string(&config).unwrap();
         let mut file = File::create(configfile).map_err(|e| human_readable_error(&e))?;
 
@@ -144,6 +392,11 @@ pub mod commands {
             instance_name: hostname,
             user: whoami::realname(),
             fdrop_dir,
+            port: super::DEFAULT_PORT,
+            bind_address: None,
+            static_peers: Vec::new(),
+            upload_limit: None,
+            blocked: Vec::new(),
         }
     }
 
@@ -154,14 +407,85 @@ pub mod commands {
 
     #[tauri::command]
     pub fn generate_keys(handle: AppHandle) -> Result<(), String> {
-        let keypair = libp2p_identity::ed25519::Keypair::generate();
         let data_dir = data_dir(&handle).map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
-        let mut identity = data_dir.clone();
-        identity.push("identity");
-        File::create(identity)
-            .and_then(|mut f| f.write_all(&keypair.to_bytes()))
-            .map_err(|e| human_readable_error(&ConfigError::KeyWriteError(e)))?;
+        super::generate_keys(&data_dir).map_err(|e| human_readable_error(&e))
+    }
 
-        Ok(())
+    #[tauri::command]
+    pub fn list_trusted_devices(handle: AppHandle) -> Result<Vec<TrustedDevice>, String> {
+        let data_dir = data_dir(&handle).map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
+        super::read_trusted_devices(&data_dir).map_err(|e| human_readable_error(&e))
+    }
+
+    #[tauri::command]
+    pub fn set_transfer_policy(
+        handle: AppHandle,
+        public_key: String,
+        policy: TransferPolicy,
+    ) -> Result<bool, String> {
+        let data_dir = data_dir(&handle).map_err(|_| ConfigError::DataDirUnresolved.to_string())?;
+        super::set_transfer_policy(&data_dir, &public_key, policy)
+            .map_err(|e| human_readable_error(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusting_a_known_device_updates_it() {
+        let data_dir = tempfile::tempdir().unwrap();
+        trust_device(data_dir.path(), "aa", "alice", None).unwrap();
+        trust_device(data_dir.path(), "bb", "bob", None).unwrap();
+        let first = find_trusted_device(data_dir.path(), "aa").unwrap();
+
+        trust_device(data_dir.path(), "aa", "alice2", Some("linux".to_string())).unwrap();
+        let devices = read_trusted_devices(data_dir.path()).unwrap();
+        assert_eq!(devices.len(), 2);
+        let device = find_trusted_device(data_dir.path(), "aa").unwrap();
+        assert_eq!(device.name, "alice2");
+        assert_eq!(device.platform.as_deref(), Some("linux"));
+        assert_eq!(device.first_seen, first.first_seen);
+        assert!(is_trusted_device(data_dir.path(), "bb"));
+        assert!(!data_dir.path().join("devices.json.tmp").exists());
+    }
+
+    #[test]
+    fn removed_device_is_no_longer_trusted() {
+        let data_dir = tempfile::tempdir().unwrap();
+        trust_device(data_dir.path(), "aa", "alice", None).unwrap();
+        trust_device(data_dir.path(), "bb", "bob", None).unwrap();
+
+        assert!(remove_trusted_device(data_dir.path(), "aa").unwrap());
+        assert!(!is_trusted_device(data_dir.path(), "aa"));
+        assert!(is_trusted_device(data_dir.path(), "bb"));
+        assert!(!remove_trusted_device(data_dir.path(), "aa").unwrap());
+    }
+
+    #[test]
+    fn device_settings_are_saved() {
+        let data_dir = tempfile::tempdir().unwrap();
+        trust_device(data_dir.path(), "aa", "alice", None).unwrap();
+        let policy = TransferPolicy {
+            auto_accept: true,
+            decline_above: Some(1_000),
+        };
+
+        assert!(set_transfer_policy(data_dir.path(), "aa", policy.clone()).unwrap());
+        assert!(set_upload_limit(data_dir.path(), "aa", Some(500)).unwrap());
+        assert_eq!(transfer_policy(data_dir.path(), "aa"), policy);
+        let device = find_trusted_device(data_dir.path(), "aa").unwrap();
+        assert_eq!(device.upload_limit, Some(500));
+        // Linking again keeps the settings
+        trust_device(data_dir.path(), "aa", "alice", None).unwrap();
+        assert_eq!(transfer_policy(data_dir.path(), "aa"), policy);
+
+        assert!(!set_transfer_policy(data_dir.path(), "bb", policy).unwrap());
+        assert!(!set_upload_limit(data_dir.path(), "bb", None).unwrap());
+        assert_eq!(
+            transfer_policy(data_dir.path(), "bb"),
+            TransferPolicy::default()
+        );
     }
 }
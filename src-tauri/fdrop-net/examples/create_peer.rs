@@ -1,7 +1,50 @@
+//! Run a peer that accepts every link request and transfer and logs what it receives
+//!
+//! The data folder must contain a configuration and an identity, as created by the app or by
+//! `fdrop-cli init`. Usage: `cargo run --example create_peer -- <data folder>`
+
+use std::{path::PathBuf, sync::Arc};
+
+use fdrop_net::{
+    BoxFuture, ConnectionManager, Frontend, LinkResponse, NetworkContext, TransferOffer,
+};
 use tracing::info;
 
+struct LogFrontend;
+
+impl Frontend for LogFrontend {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        info!("{event}: {payload}");
+    }
+
+    fn confirm_link_request<'a>(
+        &'a self,
+        their_name: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, LinkResponse> {
+        info!("accepting link request from '{their_name}' with code {code}");
+        Box::pin(async { LinkResponse::Accepted })
+    }
+
+    fn confirm_transfer<'a>(&'a self, offer: &'a TransferOffer) -> BoxFuture<'a, bool> {
+        info!("accepting '{}' from '{}'", offer.name, offer.device);
+        Box::pin(async { true })
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt().compact().init();
     info!("logging enabled");
-    fdrop_net::accept_connections().unwrap();
+    let data_dir = PathBuf::from(
+        std::env::args()
+            .nth(1)
+            .expect("usage: create_peer <data folder>"),
+    );
+    let config = fdrop_config::read_config(&data_dir).unwrap();
+    let connection_manager = ConnectionManager::new().unwrap();
+    let ctx = NetworkContext::new(Arc::new(connection_manager), config, data_dir, LogFrontend);
+    fdrop_net::start_networking(&ctx).await.unwrap();
+    tokio::signal::ctrl_c().await.unwrap();
+    ctx.connection_manager.lock().await.shutdown().unwrap();
 }
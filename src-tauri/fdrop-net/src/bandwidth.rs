@@ -0,0 +1,56 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Limits the rate at which data is written to the network
+///
+/// Writers reserve a slot for every message before writing it. The slots follow each other
+/// closely enough that the rate stays at the limit, so a throttle shared by several connections
+/// limits them together. The limit can be changed at any time and applies from the next message.
+#[derive(Clone, Debug)]
+pub(crate) struct Throttle {
+    /// Bytes per second, 0 when unlimited
+    limit: Arc<AtomicU64>,
+    /// Time from which the next message can be written
+    next_slot: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Throttle {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: Arc::new(AtomicU64::new(limit.unwrap_or(0))),
+            next_slot: Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        // Slots reserved under the old limit should not hold back the new one
+        *self.next_slot.lock().unwrap() = Instant::now();
+    }
+
+    /// Wait until `len` bytes can be written without exceeding the limit
+    pub async fn wait(&self, len: usize) {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return;
+        }
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + Duration::from_secs_f64(len as f64 / limit as f64);
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
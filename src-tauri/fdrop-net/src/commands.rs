@@ -0,0 +1,302 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use fdrop_config::ConfigError;
+use tauri::{AppHandle, Emitter, Listener, Manager, WebviewUrl};
+
+use crate::*;
+
+const LINK_RESPONSE: &str = "link-response";
+const TRANSFER_RESPONSE: &str = "transfer-response";
+
+/// Number of link request windows opened so far. Used to give each window a unique label
+static LINK_REQUEST_WINDOWS: AtomicU8 = AtomicU8::new(0);
+/// Number of transfer offer windows opened so far. Used to give each window a unique label
+static TRANSFER_OFFER_WINDOWS: AtomicU8 = AtomicU8::new(0);
+
+/// Frontend that forwards events to the webview and asks for confirmations in new windows
+struct TauriFrontend {
+    handle: AppHandle,
+}
+
+impl Frontend for TauriFrontend {
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        self.handle.emit(event, payload).unwrap();
+    }
+
+    fn confirm_link_request<'a>(
+        &'a self,
+        their_name: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, LinkResponse> {
+        Box::pin(confirm_link_request(&self.handle, their_name, code))
+    }
+
+    fn confirm_transfer<'a>(&'a self, offer: &'a TransferOffer) -> BoxFuture<'a, bool> {
+        Box::pin(confirm_transfer(&self.handle, offer))
+    }
+}
+
+/// Create confirmation window for a link request
+#[tracing::instrument(skip(handle))]
+async fn confirm_link_request(handle: &AppHandle, their_name: &str, code: &str) -> LinkResponse {
+    info!("creating confirmation window for peer");
+    let win_label = "respond-link-request-".to_string()
+        + &LINK_REQUEST_WINDOWS
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string();
+    let main = handle.get_webview_window("main").unwrap();
+    // Passed as JSON string literals since the name comes from the peer and may contain quotes
+    let name_json = serde_json::to_string(their_name).unwrap();
+    let code_json = serde_json::to_string(code).unwrap();
+    let win = tauri::WebviewWindowBuilder::new(
+        handle,
+        win_label,
+        WebviewUrl::App("/confirm-link-request".into()),
+    )
+    .title("Confirm Link Request")
+    .inner_size(600.0, 250.0)
+    .resizable(false)
+    // Set the name of the requesting device in local storage of the window so that the
+    // frontend. This is a better method than relying on tauri events which can miss if
+    // they are emitted before the frontend is fully loaded.
+    .initialization_script(&format!(
+        "localStorage.setItem('device-name', {name_json}); localStorage.setItem('verification-code', {code_json})"
+    ))
+    .parent(&main)
+    .unwrap()
+    .build()
+    .unwrap();
+
+    let (etx, erx) = flume::bounded(1);
+    win.listen(LINK_RESPONSE, move |event| match event.payload() {
+        "\"accepted\"" => etx.send(LinkResponse::Accepted).unwrap(),
+        "\"rejected\"" => etx.send(LinkResponse::Rejected).unwrap(),
+        "\"mismatch\"" => etx.send(LinkResponse::CodeMismatch).unwrap(),
+        "\"blocked\"" => etx.send(LinkResponse::Blocked).unwrap(),
+        _ => etx.send(LinkResponse::Other).unwrap(),
+    });
+    erx.recv_async().await.unwrap()
+}
+
+/// Create confirmation window for a file or directory offered by a device
+#[tracing::instrument(skip(handle))]
+async fn confirm_transfer(handle: &AppHandle, offer: &TransferOffer) -> bool {
+    info!("creating confirmation window for transfer offer");
+    let win_label = "respond-transfer-offer-".to_string()
+        + &TRANSFER_OFFER_WINDOWS
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string();
+    let main = handle.get_webview_window("main").unwrap();
+    // The offer is passed as a JSON string literal since the names in it come from the peer and
+    // may contain quotes
+    let offer_json = serde_json::to_string(&serde_json::to_string(offer).unwrap()).unwrap();
+    let win = tauri::WebviewWindowBuilder::new(
+        handle,
+        win_label,
+        WebviewUrl::App("/confirm-transfer".into()),
+    )
+    .title("Incoming Transfer")
+    .inner_size(500.0, 250.0)
+    .resizable(false)
+    .initialization_script(&format!(
+        "localStorage.setItem('transfer-offer', {offer_json})"
+    ))
+    .parent(&main)
+    .unwrap()
+    .build()
+    .unwrap();
+
+    let (etx, erx) = flume::bounded(1);
+    let etx2 = etx.clone();
+    win.listen(TRANSFER_RESPONSE, move |event| {
+        let _ = etx.try_send(event.payload() == "\"accepted\"");
+    });
+    // Closing the window without answering declines the transfer
+    win.on_window_event(move |event| {
+        if matches!(event, tauri::WindowEvent::Destroyed) {
+            let _ = etx2.try_send(false);
+        }
+    });
+    erx.recv_async().await.unwrap_or(false)
+}
+
+#[tauri::command]
+pub async fn enable_networking(handle: AppHandle) -> Result<(), String> {
+    let data_dir = fdrop_config::data_dir(&handle)
+        .map_err(|_| NetworkError::from(ConfigError::DataDirUnresolved))?;
+    let config = fdrop_config::read_config(&data_dir).map_err(|e| NetworkError::from(e))?;
+    let connection_manager = handle
+        .state::<Arc<Mutex<ConnectionManager>>>()
+        .inner()
+        .clone();
+    let ctx = NetworkContext::new(
+        connection_manager,
+        config,
+        data_dir,
+        TauriFrontend {
+            handle: handle.clone(),
+        },
+    );
+    handle.manage(ctx.clone());
+    start_networking(&ctx).await?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn send_text_message(
+    handle: AppHandle,
+    cname: String,
+    contents: String,
+) -> Result<u32, String> {
+    let ctx = handle.state::<NetworkContext>();
+    Ok(super::send_text_message(&ctx, &cname, contents).await?)
+}
+
+#[tauri::command]
+pub async fn send_files(
+    handle: AppHandle,
+    cname: String,
+    file_paths: Vec<String>,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let ctx = handle.state::<NetworkContext>();
+    let file_paths = file_paths.into_iter().map(PathBuf::from).collect();
+    super::send_files(&ctx, &cname, file_paths, assoc_text).await
+}
+
+#[tauri::command]
+pub async fn send_directory(
+    handle: AppHandle,
+    cname: String,
+    dir_path: String,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let ctx = handle.state::<NetworkContext>();
+    super::send_directory(&ctx, &cname, Path::new(&dir_path), assoc_text).await
+}
+
+#[tauri::command]
+pub async fn cancel_transfer(
+    handle: AppHandle,
+    cname: String,
+    transfer_id: u32,
+    direction: Direction,
+) -> Result<(), String> {
+    let ctx = handle.state::<NetworkContext>();
+    super::cancel_transfer(&ctx, &cname, transfer_id, direction).await?;
+    Ok(())
+}
+
+/// Limit the upload rate to the device `cname`, or to all devices together if no device is given
+#[tauri::command]
+pub async fn set_upload_limit(
+    handle: AppHandle,
+    cname: Option<String>,
+    limit: Option<u64>,
+) -> Result<(), String> {
+    let ctx = handle.state::<NetworkContext>();
+    match cname {
+        Some(cname) => super::set_device_upload_limit(&ctx, &cname, limit).await?,
+        None => super::set_upload_limit(&ctx, limit).await?,
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn active_transfers(handle: AppHandle) -> Vec<TransferProgress> {
+    super::active_transfers(&handle.state::<NetworkContext>())
+}
+
+/// Transfers exchanged with the device `cname`, one page of `limit` entries at a time from the
+/// most recent one
+#[tauri::command]
+pub async fn transfer_history(
+    handle: AppHandle,
+    cname: String,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<HistoryEntry>, String> {
+    let ctx = handle.state::<NetworkContext>();
+    Ok(super::transfer_history(&ctx, &cname, offset, limit).await?)
+}
+
+/// Text messages and file offers waiting for the device `cname` to be linked again
+#[tauri::command]
+pub async fn queued_items(handle: AppHandle, cname: String) -> Result<Vec<QueuedItem>, String> {
+    let ctx = handle.state::<NetworkContext>();
+    Ok(super::queued_items(&ctx, &cname).await?)
+}
+
+#[tauri::command]
+pub async fn add_peer(
+    handle: AppHandle,
+    host: String,
+    port: Option<u16>,
+    remember: bool,
+) -> Result<ConnectionInfo, String> {
+    let ctx = handle.state::<NetworkContext>();
+    let port = port.unwrap_or(fdrop_config::DEFAULT_PORT);
+    Ok(super::add_peer(&ctx, &host, port, remember).await?)
+}
+
+/// End the link with the device `cname` on both sides
+#[tauri::command]
+pub async fn unlink_device(handle: AppHandle, cname: String) -> Result<(), String> {
+    let ctx = handle.state::<NetworkContext>();
+    super::unlink_device(&ctx, &cname).await?;
+    Ok(())
+}
+
+/// Stop trusting the device with the identity `public_key`, ending the link if it is connected
+#[tauri::command]
+pub async fn remove_trusted_device(handle: AppHandle, public_key: String) -> Result<bool, String> {
+    let ctx = handle.state::<NetworkContext>();
+    Ok(super::remove_trusted_device(&ctx, &public_key).await?)
+}
+
+#[tauri::command]
+pub async fn link_device_by_name(handle: AppHandle, cname: String) -> Result<&'static str, String> {
+    let res = {
+        let ctx = handle.state::<NetworkContext>();
+        link_device(&ctx, &cname).await?
+    };
+    let res = match res {
+        LinkResponse::Accepted => Ok("accepted"),
+        LinkResponse::Rejected | LinkResponse::CodeMismatch | LinkResponse::Blocked => {
+            let win_label = "rejected-link-request-".to_string()
+                + &LINK_REQUEST_WINDOWS
+                    .fetch_add(1, Ordering::Relaxed)
+                    .to_string();
+
+            let main = handle.get_webview_window("main").unwrap();
+            let name_json = serde_json::to_string(&cname).unwrap();
+            tauri::WebviewWindowBuilder::new(
+                &handle,
+                win_label,
+                WebviewUrl::App("/rejected-link-request".into()),
+            )
+            .title("Link Request Rejected")
+            .inner_size(500.0, 150.0)
+            .resizable(false)
+            // Set the name of the requesting device in local storage of the window so that the
+            // frontend. This is a better method than relying on tauri events which can miss if
+            // they are emitted before the frontend is fully loaded.
+            .initialization_script(&format!(
+                "localStorage.setItem('device-name', {}); localStorage.setItem('code-mismatch', '{}')",
+                name_json,
+                res == LinkResponse::CodeMismatch
+            ))
+            .parent(&main)
+            .unwrap()
+            .build()
+            .unwrap();
+            if res == LinkResponse::CodeMismatch {
+                Ok("code-mismatch")
+            } else {
+                Ok("rejected")
+            }
+        }
+        LinkResponse::Other => Ok("other"),
+    };
+    res
+}
@@ -1,10 +1,18 @@
 use fdrop_common::human_readable_error;
+use fdrop_config::ConfigError;
 #[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum NetworkError {
     #[error("discovery error")]
     DiscoveryError(#[from] DiscoveryError),
     #[error("failed to communicate on the network")]
     CommunicationError(#[from] CommunicationError),
+    #[error("failed to load configuration")]
+    ConfigError(#[from] ConfigError),
+    #[error("failed to read the transfer history")]
+    HistoryError(std::io::Error),
+    #[error("failed to access the items queued for offline devices")]
+    QueueError(std::io::Error),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -15,17 +23,48 @@ pub enum CommunicationError {
     ReadError(std::io::Error),
     #[error("failed to decode peer message")]
     DecodeError,
+    #[error("message is too large to be sent")]
+    MessageTooLarge,
     #[error("no reachable address for the peer")]
     NoReachableAddress,
+    #[error("failed to resolve the address of the peer")]
+    AddressResolution(std::io::Error),
     #[error("peer sent unexpected messages before linking")]
     Unauthenticated,
     #[error("peer not found by discovery service")]
     PeerNotFound,
+    #[error("device is not linked")]
+    NotLinked,
+    #[error("the connection to the device was closed")]
+    Disconnected,
+    #[error("the device declined the transfer: {0}")]
+    TransferDeclined(String),
+    #[error("the transfer was cancelled")]
+    TransferCancelled,
+    #[error("the device failed to receive the transfer: {0}")]
+    TransferFailed(String),
+    #[error("peer did not respond to the transfer")]
+    NoResponse,
+    #[error("no such transfer")]
+    TransferNotFound,
+    #[error("received file does not match the checksum sent by the peer")]
+    ChecksumMismatch,
+    #[error("received file does not have the size announced by the peer")]
+    SizeMismatch,
+    #[error("failed to establish a secure channel with the peer")]
+    HandshakeError,
+    #[error("failed to encrypt message for the peer")]
+    EncryptError,
+    #[error("failed to decrypt peer message")]
+    DecryptError,
+    #[error("peer identity does not match the identity of the device")]
+    IdentityMismatch,
     #[error("IO error")]
     Io(#[from] std::io::Error),
 }
 
 #[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DiscoveryError {
     #[error("service error")]
     ServiceError(mdns_sd::Error),
@@ -39,8 +78,6 @@ pub enum DiscoveryError {
     HostnameError(std::io::Error),
     #[error("mDNS shutdown error")]
     ShutdownError(mdns_sd::Error),
-    #[error(transparent)]
-    TauriError(#[from] tauri::Error),
 }
 
 impl From<NetworkError> for String {
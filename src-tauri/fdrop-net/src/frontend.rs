@@ -0,0 +1,28 @@
+use std::{future::Future, pin::Pin};
+
+use crate::{protocol::LinkResponse, TransferOffer};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The user interface that the networking core reports to
+///
+/// The core never talks to the user directly. Everything that should be shown to the user is sent
+/// as an event through [`Frontend::emit`] and decisions are requested through the other methods.
+pub trait Frontend: Send + Sync + 'static {
+    /// Notify the user interface of `event`. The payload is the JSON form of the structs in this
+    /// crate, for example [`ConnectionInfo`](crate::ConnectionInfo) for `device-discovered`
+    fn emit(&self, event: &str, payload: serde_json::Value);
+
+    /// Ask the user whether the link request from the device `their_name` should be accepted.
+    /// `code` is the verification code which the user should compare with the one shown on the
+    /// other device
+    fn confirm_link_request<'a>(
+        &'a self,
+        their_name: &'a str,
+        code: &'a str,
+    ) -> BoxFuture<'a, LinkResponse>;
+
+    /// Ask the user whether the file or directory offered by another device should be received.
+    /// Only called for offers that the transfer policy of the device does not decide on its own
+    fn confirm_transfer<'a>(&'a self, offer: &'a TransferOffer) -> BoxFuture<'a, bool>;
+}
@@ -0,0 +1,964 @@
+//! Peers that run fully inside one process. Each peer has its own data folder, identity and
+//! listener on loopback, and the peers learn about each other without mDNS.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use fdrop_config::{TransferPolicy, UserConfig};
+use flume::{Receiver, Sender};
+use libp2p::identity::ed25519;
+use serde_json::Value;
+use tempfile::TempDir;
+use tokio::sync::Mutex;
+
+use crate::{
+    accept_connections,
+    bandwidth::Throttle,
+    bind_listener,
+    errors::CommunicationError,
+    full_name,
+    history::History,
+    protocol::{self, TransferType},
+    queue::OfflineQueue,
+    transfer::{ActiveTransfers, OutgoingState, PendingTransfers},
+    BoxFuture, Connection, ConnectionInfo, ConnectionManager, ConnectionState, Frontend,
+    LinkResponse, NetworkContext, TransferOffer,
+};
+
+/// How long to wait for an event before failing the test
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Event recorded by [`TestFrontend`] when the user is asked about a transfer offer
+const OFFER: &str = "test-transfer-offer";
+
+/// Frontend that records the events and answers link requests with a fixed response. Transfer
+/// offers are accepted unless told otherwise
+pub(crate) struct TestFrontend {
+    response: LinkResponse,
+    accept_transfers: AtomicBool,
+    /// Leave transfer offers unanswered
+    hold_transfers: AtomicBool,
+    /// Number of link requests the user was asked about
+    link_requests: AtomicU32,
+    /// Number of transfer offers the user was asked about
+    offers: AtomicU32,
+    tx: Sender<(String, Value)>,
+    rx: Receiver<(String, Value)>,
+    /// Data folder of the peer. It is removed once the last handle to the context is gone
+    _data_dir: TempDir,
+}
+
+impl TestFrontend {
+    pub fn new(response: LinkResponse, data_dir: TempDir) -> Self {
+        let (tx, rx) = flume::unbounded();
+        Self {
+            response,
+            accept_transfers: AtomicBool::new(true),
+            hold_transfers: AtomicBool::new(false),
+            link_requests: AtomicU32::new(0),
+            offers: AtomicU32::new(0),
+            tx,
+            rx,
+            _data_dir: data_dir,
+        }
+    }
+
+    pub fn decline_transfers(&self) {
+        self.accept_transfers.store(false, Ordering::Relaxed);
+    }
+
+    pub fn hold_transfers(&self) {
+        self.hold_transfers.store(true, Ordering::Relaxed);
+    }
+
+    pub fn link_requests(&self) -> u32 {
+        self.link_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn offers(&self) -> u32 {
+        self.offers.load(Ordering::Relaxed)
+    }
+
+    /// Forget the events emitted so far
+    pub fn clear_events(&self) {
+        self.rx.drain();
+    }
+
+    /// Wait for the next `event`, skipping any other events emitted before it
+    pub async fn next_event(&self, event: &str) -> Value {
+        tokio::time::timeout(EVENT_TIMEOUT, async {
+            loop {
+                let (name, payload) = self.rx.recv_async().await.unwrap();
+                if name == event {
+                    return payload;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for '{event}'"))
+    }
+}
+
+impl Frontend for TestFrontend {
+    fn emit(&self, event: &str, payload: Value) {
+        let _ = self.tx.send((event.to_string(), payload));
+    }
+
+    fn confirm_link_request<'a>(
+        &'a self,
+        _their_name: &'a str,
+        _code: &'a str,
+    ) -> BoxFuture<'a, LinkResponse> {
+        self.link_requests.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async move { self.response })
+    }
+
+    fn confirm_transfer<'a>(&'a self, offer: &'a TransferOffer) -> BoxFuture<'a, bool> {
+        self.offers.fetch_add(1, Ordering::Relaxed);
+        self.emit(OFFER, serde_json::to_value(offer).unwrap());
+        Box::pin(async move {
+            if self.hold_transfers.load(Ordering::Relaxed) {
+                std::future::pending::<()>().await;
+            }
+            self.accept_transfers.load(Ordering::Relaxed)
+        })
+    }
+}
+
+/// Create a context with a fresh data folder and identity that answers link requests with
+/// `response`. Nothing is started on the network
+pub(crate) fn test_context(
+    name: &str,
+    response: LinkResponse,
+) -> (NetworkContext, Arc<TestFrontend>) {
+    let temp_dir = tempfile::Builder::new()
+        .prefix("fdrop-test-")
+        .tempdir()
+        .unwrap();
+    let data_dir = temp_dir.path().to_path_buf();
+    let config = UserConfig {
+        user: "test".to_string(),
+        instance_name: name.to_string(),
+        fdrop_dir: data_dir.join("FDrop"),
+        // Let the system pick a free port so that any number of peers can run side by side
+        port: 0,
+        bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        static_peers: Vec::new(),
+        upload_limit: None,
+        blocked: Vec::new(),
+    };
+    fdrop_config::save_config(&data_dir, &config).unwrap();
+    fdrop_config::generate_keys(&data_dir).unwrap();
+
+    let mut connection_manager = ConnectionManager::new().unwrap().into_inner();
+    connection_manager.instance_name = Some(full_name(name));
+    connection_manager.keypair = Some(fdrop_config::read_keys_from(&data_dir).unwrap());
+    let frontend = Arc::new(TestFrontend::new(response, temp_dir));
+    let ctx = NetworkContext {
+        connection_manager: Arc::new(Mutex::new(connection_manager)),
+        config: Arc::new(Mutex::new(config)),
+        history: History::new(&data_dir),
+        queue: OfflineQueue::new(&data_dir),
+        data_dir,
+        frontend: frontend.clone(),
+        transfers: ActiveTransfers::default(),
+        upload_limit: Throttle::default(),
+    };
+    (ctx, frontend)
+}
+
+/// A device running on loopback
+pub(crate) struct Peer {
+    pub ctx: NetworkContext,
+    pub frontend: Arc<TestFrontend>,
+    /// Name under which the other peers know this one
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+impl Peer {
+    /// Start a peer that answers link requests with `response`
+    pub async fn start(name: &str, response: LinkResponse) -> Self {
+        Self::start_on(name, response, 0).await
+    }
+
+    /// Start a peer that accepts connections on `port`
+    pub async fn start_on(name: &str, response: LinkResponse, port: u16) -> Self {
+        let (ctx, frontend) = test_context(name, response);
+        let bind_address = ctx.config.lock().await.bind_address;
+        let listener = bind_listener(bind_address, port).unwrap();
+        let address = listener.local_addr().unwrap();
+        ctx.connection_manager.lock().await.listen_port = Some(address.port());
+        accept_connections(ctx.clone(), listener).await.unwrap();
+        Self {
+            ctx,
+            frontend,
+            name: full_name(name),
+            address,
+        }
+    }
+
+    /// Make this peer known to `other` as if `other` had discovered it
+    pub async fn announce_to(&self, other: &Peer) {
+        let con = self.discovered().await;
+        other
+            .ctx
+            .connection_manager
+            .lock()
+            .await
+            .available_connections
+            .insert(self.name.clone(), con);
+    }
+
+    /// This peer as it is found by discovery
+    pub async fn discovered(&self) -> Connection {
+        let keypair = self.keypair().await;
+        Connection {
+            info: ConnectionInfo {
+                name: self.name.clone(),
+                linked: false,
+                state: ConnectionState::Offline,
+                platform: None,
+            },
+            addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: self.address.port(),
+            outbox: None,
+            pending: PendingTransfers::default(),
+            public_key: Some(keypair.public()),
+            added_by_address: false,
+        }
+    }
+
+    pub async fn keypair(&self) -> ed25519::Keypair {
+        let connection_manager = self.ctx.connection_manager.lock().await;
+        connection_manager.keypair.clone().unwrap()
+    }
+
+    pub async fn trusts(&self, other: &Peer) -> bool {
+        let public_key = hex::encode(other.keypair().await.public().to_bytes());
+        fdrop_config::is_trusted_device(&self.ctx.data_dir, &public_key)
+    }
+
+    /// Set how the transfers offered by `other` are answered
+    pub async fn set_policy(&self, other: &Peer, policy: TransferPolicy) {
+        let public_key = hex::encode(other.keypair().await.public().to_bytes());
+        assert!(
+            fdrop_config::set_transfer_policy(&self.ctx.data_dir, &public_key, policy).unwrap()
+        );
+    }
+
+    /// Folder where this peer saves the files it receives
+    pub async fn fdrop_dir(&self) -> std::path::PathBuf {
+        self.ctx.config.lock().await.fdrop_dir.clone()
+    }
+}
+
+/// Start two peers that know about each other. `bob` answers link requests with `response`
+pub(crate) async fn peer_pair(response: LinkResponse) -> (Peer, Peer) {
+    let alice = Peer::start("alice", LinkResponse::Accepted).await;
+    let bob = Peer::start("bob", response).await;
+    alice.announce_to(&bob).await;
+    bob.announce_to(&alice).await;
+    (alice, bob)
+}
+
+/// Start two peers and link `alice` with `bob`
+pub(crate) async fn linked_pair() -> (Peer, Peer) {
+    let (alice, bob) = peer_pair(LinkResponse::Accepted).await;
+    let resp = crate::link_device(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::Accepted);
+    bob.frontend.next_event(crate::DEVICE_LINKED).await;
+    (alice, bob)
+}
+
+fn write_file(path: &Path, len: usize) -> Vec<u8> {
+    let contents: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, &contents).unwrap();
+    contents
+}
+
+#[tokio::test]
+async fn linked_peers_exchange_text() {
+    let (alice, bob) = linked_pair().await;
+    assert!(alice.trusts(&bob).await);
+    assert!(bob.trusts(&alice).await);
+
+    let message_id = crate::send_text_message(&alice.ctx, &bob.name, "hello bob".to_string())
+        .await
+        .unwrap();
+    let transfer = bob.frontend.next_event(crate::TRANSFER).await;
+    assert_eq!(transfer["display_content"], "hello bob");
+    let sent = alice.frontend.next_event(crate::MESSAGE_RECEIPT).await;
+    assert_eq!(sent["status"], "sent");
+    let delivered = alice.frontend.next_event(crate::MESSAGE_RECEIPT).await;
+    assert_eq!(delivered["message_id"], message_id);
+    assert_eq!(delivered["status"], "delivered");
+
+    // The peer that accepted the link can message back over the same connection
+    crate::send_text_message(&bob.ctx, &alice.name, "hello alice".to_string())
+        .await
+        .unwrap();
+    let transfer = alice.frontend.next_event(crate::TRANSFER).await;
+    assert_eq!(transfer["display_content"], "hello alice");
+}
+
+#[tokio::test]
+async fn file_arrives_intact() {
+    let (alice, bob) = linked_pair().await;
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    // Spans several chunks with a partial one at the end
+    let contents = write_file(&path, 100_000);
+
+    crate::send_files(&alice.ctx, &bob.name, vec![path], None)
+        .await
+        .unwrap();
+    alice.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+
+    let received = std::fs::read(bob.fdrop_dir().await.join("data.bin")).unwrap();
+    assert!(received == contents);
+}
+
+#[tokio::test]
+async fn partially_received_file_is_resumed() {
+    let (alice, bob) = linked_pair().await;
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    let contents = write_file(&path, 100_000);
+    // Bob already got the start of the file before the link dropped
+    let destination = bob.fdrop_dir().await.join("data.bin");
+    let partial_path = crate::transfer::partial_path(&bob.ctx.data_dir, &destination, 100_000);
+    std::fs::create_dir_all(partial_path.parent().unwrap()).unwrap();
+    std::fs::write(&partial_path, &contents[..40_000]).unwrap();
+
+    crate::send_files(&alice.ctx, &bob.name, vec![path], None)
+        .await
+        .unwrap();
+    // Both sides pick up after what bob had, so the first chunk ends past it
+    for peer in [&alice, &bob] {
+        let progress = peer.frontend.next_event(crate::TRANSFER_PROGRESS).await;
+        assert_eq!(progress["bytes_done"], 40_000 + crate::FILE_CHUNK_SIZE);
+    }
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+
+    let received = std::fs::read(&destination).unwrap();
+    assert!(received == contents);
+    assert!(!partial_path.exists());
+}
+
+#[tokio::test]
+async fn directory_keeps_its_structure() {
+    let (alice, bob) = linked_pair().await;
+    let root = alice.ctx.data_dir.join("photos");
+    let first = write_file(&root.join("a.jpg"), 2_000);
+    let second = write_file(&root.join("2024").join("summer").join("b.jpg"), 20_000);
+    std::fs::create_dir_all(root.join("empty")).unwrap();
+
+    crate::send_directory(&alice.ctx, &bob.name, &root, None)
+        .await
+        .unwrap();
+    loop {
+        let progress = bob.frontend.next_event(crate::DIRECTORY_PROGRESS).await;
+        if progress["files_done"] == progress["file_count"] && progress["files_done"] != 0 {
+            break;
+        }
+    }
+
+    let received = bob.fdrop_dir().await.join("photos");
+    assert!(std::fs::read(received.join("a.jpg")).unwrap() == first);
+    assert!(std::fs::read(received.join("2024/summer/b.jpg")).unwrap() == second);
+    assert!(received.join("empty").is_dir());
+}
+
+#[tokio::test]
+async fn rejected_link_leaves_peers_unlinked() {
+    let (alice, bob) = peer_pair(LinkResponse::Rejected).await;
+
+    let resp = crate::link_device(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::Rejected);
+    assert!(!alice.trusts(&bob).await);
+    assert!(!bob.trusts(&alice).await);
+
+    let res = crate::send_text_message(&alice.ctx, &bob.name, "hello".to_string()).await;
+    assert!(matches!(
+        res,
+        Err(crate::NetworkError::CommunicationError(
+            CommunicationError::NotLinked
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn code_mismatch_is_reported_to_sender() {
+    let (alice, bob) = peer_pair(LinkResponse::CodeMismatch).await;
+
+    let resp = crate::link_device(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::CodeMismatch);
+    assert!(!bob.trusts(&alice).await);
+}
+
+#[tokio::test]
+async fn undiscovered_device_can_link() {
+    let alice = Peer::start("alice", LinkResponse::Accepted).await;
+    let bob = Peer::start("bob", LinkResponse::Accepted).await;
+    // Only alice knows about bob, as on a network that filters multicast
+    bob.announce_to(&alice).await;
+
+    let resp = crate::link_device(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::Accepted);
+    let discovered = bob.frontend.next_event(crate::DEVICE_DISCOVERED).await;
+    assert_eq!(discovered["name"], alice.name.as_str());
+    bob.frontend.next_event(crate::DEVICE_LINKED).await;
+    assert!(bob.trusts(&alice).await);
+
+    crate::send_text_message(&bob.ctx, &alice.name, "found you".to_string())
+        .await
+        .unwrap();
+    let transfer = alice.frontend.next_event(crate::TRANSFER).await;
+    assert_eq!(transfer["display_content"], "found you");
+}
+
+#[tokio::test]
+async fn peer_added_by_address_is_renamed_once_linked() {
+    let alice = Peer::start("alice", LinkResponse::Accepted).await;
+    let bob = Peer::start("bob", LinkResponse::Accepted).await;
+
+    let port = bob.address.port();
+    let info = crate::add_peer(&alice.ctx, "127.0.0.1", port, true)
+        .await
+        .unwrap();
+    assert_eq!(info.name, format!("127.0.0.1:{port}"));
+    let resp = crate::link_device(&alice.ctx, &info.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::Accepted);
+
+    let removed = alice.frontend.next_event(crate::DEVICE_REMOVED).await;
+    assert_eq!(removed["name"], info.name.as_str());
+    let linked = alice.frontend.next_event(crate::DEVICE_LINKED).await;
+    assert_eq!(linked["name"], bob.name.as_str());
+    let saved = fdrop_config::read_config(&alice.ctx.data_dir).unwrap();
+    assert_eq!(saved.static_peers.len(), 1);
+    assert_eq!(saved.static_peers[0].port, port);
+}
+
+#[tokio::test]
+async fn peer_added_by_address_cannot_take_the_name_of_another_device() {
+    let (alice, bob) = peer_pair(LinkResponse::Accepted).await;
+    // Claims the name of bob, but has an identity of its own
+    let impostor = Peer::start("bob", LinkResponse::Accepted).await;
+
+    let port = impostor.address.port();
+    let info = crate::add_peer(&alice.ctx, "127.0.0.1", port, false)
+        .await
+        .unwrap();
+    let res = crate::link_device(&alice.ctx, &info.name).await;
+    assert!(matches!(
+        res,
+        Err(crate::NetworkError::CommunicationError(
+            CommunicationError::IdentityMismatch
+        ))
+    ));
+
+    let connection_manager = alice.ctx.connection_manager.lock().await;
+    let con = connection_manager.get_connection(&bob.name).unwrap();
+    assert_eq!(con.public_key, Some(bob.keypair().await.public()));
+    assert!(con.outbox.is_none());
+    assert!(connection_manager.get_connection(&info.name).is_some());
+}
+
+#[tokio::test]
+async fn link_request_is_retried_until_device_is_reachable() {
+    let alice = Peer::start("alice", LinkResponse::Accepted).await;
+    // Reserve a port on which bob starts listening only later
+    let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let info = crate::add_peer(&alice.ctx, "127.0.0.1", port, false)
+        .await
+        .unwrap();
+
+    let ctx = alice.ctx.clone();
+    let link = tokio::spawn(async move { crate::link_device(&ctx, &info.name).await });
+    let connecting = alice.frontend.next_event(crate::DEVICE_CONNECTING).await;
+    assert_eq!(connecting["state"], "connecting");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let bob = Peer::start_on("bob", LinkResponse::Accepted, port).await;
+
+    let resp = link.await.unwrap().unwrap();
+    assert_eq!(resp, LinkResponse::Accepted);
+    let linked = alice.frontend.next_event(crate::DEVICE_LINKED).await;
+    assert_eq!(linked["name"], bob.name.as_str());
+    assert_eq!(linked["state"], "linked");
+}
+
+#[tokio::test]
+async fn messages_to_offline_device_are_delivered_on_relink() {
+    let (alice, bob) = linked_pair().await;
+    // Bob leaves the network as far as alice can tell
+    alice
+        .ctx
+        .connection_manager
+        .lock()
+        .await
+        .available_connections
+        .remove(&bob.name);
+
+    for text in ["first", "second"] {
+        crate::send_text_message(&alice.ctx, &bob.name, text.to_string())
+            .await
+            .unwrap();
+        let queued = alice.frontend.next_event(crate::DELIVERY).await;
+        assert_eq!(queued["state"], "queued");
+    }
+    let items = crate::queued_items(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(items.len(), 2);
+
+    bob.announce_to(&alice).await;
+    let resp = crate::link_device(&alice.ctx, &bob.name).await.unwrap();
+    assert_eq!(resp, LinkResponse::Accepted);
+    for text in ["first", "second"] {
+        let transfer = bob.frontend.next_event(crate::TRANSFER).await;
+        assert_eq!(transfer["display_content"], text);
+    }
+    let mut delivered = 0;
+    while delivered < 2 {
+        let delivery = alice.frontend.next_event(crate::DELIVERY).await;
+        if delivery["state"] == "delivered" {
+            delivered += 1;
+        }
+    }
+    assert!(crate::queued_items(&alice.ctx, &bob.name)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+/// End the stream between `alice` and `bob` while both keep trusting each other, as when the link
+/// drops
+async fn drop_link(alice: &Peer, bob: &Peer) {
+    crate::unlink_device(&alice.ctx, &bob.name).await.unwrap();
+    bob.frontend.next_event(crate::DEVICE_UNLINKED).await;
+    for (peer, other) in [(alice, bob), (bob, alice)] {
+        let public_key = hex::encode(other.keypair().await.public().to_bytes());
+        fdrop_config::trust_device(&peer.ctx.data_dir, &public_key, &other.name, None).unwrap();
+        peer.frontend.clear_events();
+    }
+}
+
+#[tokio::test]
+async fn trusted_device_is_relinked_when_rediscovered() {
+    let (alice, bob) = linked_pair().await;
+    drop_link(&alice, &bob).await;
+    // Only the peer with the smaller identity key links again, the other one waits for it
+    let alice_key = alice.keypair().await.public().to_bytes();
+    let bob_key = bob.keypair().await.public().to_bytes();
+    let (linking, waiting) = if alice_key < bob_key {
+        (&alice, &bob)
+    } else {
+        (&bob, &alice)
+    };
+    let keypair = waiting.keypair().await;
+    let con = linking.discovered().await;
+    assert!(!crate::should_relink(&waiting.ctx, Some(&keypair), &con));
+    let requests = waiting.frontend.link_requests();
+
+    crate::device_resolved(&linking.ctx, waiting.discovered().await).await;
+    let linked = linking.frontend.next_event(crate::DEVICE_LINKED).await;
+    assert_eq!(linked["name"], waiting.name.as_str());
+    waiting.frontend.next_event(crate::DEVICE_LINKED).await;
+    // Trusted devices are linked with without asking
+    assert_eq!(waiting.frontend.link_requests(), requests);
+}
+
+#[tokio::test]
+async fn unlinked_device_is_forgotten_on_both_sides() {
+    let (alice, bob) = linked_pair().await;
+
+    crate::unlink_device(&alice.ctx, &bob.name).await.unwrap();
+    let unlinked = bob.frontend.next_event(crate::DEVICE_UNLINKED).await;
+    assert_eq!(unlinked["name"], alice.name.as_str());
+    assert_eq!(unlinked["state"], "offline");
+    alice.frontend.next_event(crate::DEVICE_UNLINKED).await;
+    assert!(!alice.trusts(&bob).await);
+    assert!(!bob.trusts(&alice).await);
+
+    let res = crate::send_text_message(&bob.ctx, &alice.name, "hello?".to_string()).await;
+    assert!(matches!(
+        res,
+        Err(crate::NetworkError::CommunicationError(
+            CommunicationError::NotLinked
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn removing_trusted_device_ends_the_link() {
+    let (alice, bob) = linked_pair().await;
+    let public_key = hex::encode(bob.keypair().await.public().to_bytes());
+
+    assert!(crate::remove_trusted_device(&alice.ctx, &public_key)
+        .await
+        .unwrap());
+    assert!(!alice.trusts(&bob).await);
+    bob.frontend.next_event(crate::DEVICE_UNLINKED).await;
+    let res = crate::send_text_message(&alice.ctx, &bob.name, "hello".to_string()).await;
+    assert!(res.is_err());
+    assert!(!crate::remove_trusted_device(&alice.ctx, &public_key)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn declined_file_is_not_saved() {
+    let (alice, bob) = linked_pair().await;
+    bob.frontend.decline_transfers();
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 1_000);
+
+    let res = crate::send_files(&alice.ctx, &bob.name, vec![path], None).await;
+    assert!(res.unwrap_err().contains("declined by the user"));
+    assert_eq!(bob.frontend.offers(), 1);
+    assert_eq!(std::fs::read_dir(bob.fdrop_dir().await).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn declined_directory_is_not_sent() {
+    let (alice, bob) = linked_pair().await;
+    bob.frontend.decline_transfers();
+    let root = alice.ctx.data_dir.join("photos");
+    write_file(&root.join("a.jpg"), 1_000);
+
+    let res = crate::send_directory(&alice.ctx, &bob.name, &root, None).await;
+    assert!(res.is_err());
+    assert!(!bob.fdrop_dir().await.join("photos").exists());
+}
+
+#[tokio::test]
+async fn policy_answers_without_asking() {
+    let (alice, bob) = linked_pair().await;
+    bob.set_policy(
+        &alice,
+        TransferPolicy {
+            auto_accept: true,
+            decline_above: Some(10_000),
+        },
+    )
+    .await;
+    let small = alice.ctx.data_dir.join("outgoing").join("small.bin");
+    let contents = write_file(&small, 1_000);
+    let large = alice.ctx.data_dir.join("outgoing").join("large.bin");
+    write_file(&large, 20_000);
+
+    let res = crate::send_files(&alice.ctx, &bob.name, vec![large], None).await;
+    assert!(res.unwrap_err().contains("larger than 10000 bytes"));
+    crate::send_files(&alice.ctx, &bob.name, vec![small], None)
+        .await
+        .unwrap();
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+
+    assert_eq!(bob.frontend.offers(), 0);
+    let received = std::fs::read(bob.fdrop_dir().await.join("small.bin")).unwrap();
+    assert!(received == contents);
+    assert!(!bob.fdrop_dir().await.join("large.bin").exists());
+}
+
+#[tokio::test]
+async fn progress_is_reported_on_both_sides() {
+    let (alice, bob) = linked_pair().await;
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 100_000);
+
+    crate::send_files(&alice.ctx, &bob.name, vec![path], None)
+        .await
+        .unwrap();
+    for (peer, other, direction) in [(&alice, &bob, "outgoing"), (&bob, &alice, "incoming")] {
+        loop {
+            let progress = peer.frontend.next_event(crate::TRANSFER_PROGRESS).await;
+            assert_eq!(progress["direction"], direction);
+            assert_eq!(progress["device"], other.name.as_str());
+            assert_eq!(progress["file_name"], "data.bin");
+            assert_eq!(progress["total_size"], 100_000);
+            if progress["bytes_done"] == 100_000 {
+                break;
+            }
+        }
+    }
+    // The sender is done with the file once `send_files` returns
+    assert!(crate::active_transfers(&alice.ctx).is_empty());
+}
+
+/// Start sending a file from `alice` to `bob`, who holds on to the offer. Returns the task sending
+/// the file and the id of the transfer
+async fn held_transfer(
+    alice: &Peer,
+    bob: &Peer,
+) -> (tokio::task::JoinHandle<Result<(), String>>, u32) {
+    bob.frontend.hold_transfers();
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 1_000);
+    let ctx = alice.ctx.clone();
+    let name = bob.name.clone();
+    let sending =
+        tokio::spawn(async move { crate::send_files(&ctx, &name, vec![path], None).await });
+    let offer = bob.frontend.next_event(OFFER).await;
+    (sending, offer["transfer_id"].as_u64().unwrap() as u32)
+}
+
+#[tokio::test]
+async fn sender_can_cancel_transfer() {
+    let (alice, bob) = linked_pair().await;
+    let (sending, transfer_id) = held_transfer(&alice, &bob).await;
+
+    crate::cancel_transfer(
+        &alice.ctx,
+        &bob.name,
+        transfer_id,
+        crate::Direction::Outgoing,
+    )
+    .await
+    .unwrap();
+    let res = sending.await.unwrap();
+    assert!(res.unwrap_err().contains("cancelled"));
+    let cancelled = bob.frontend.next_event(crate::TRANSFER_CANCELLED).await;
+    assert_eq!(cancelled["transfer_id"], transfer_id);
+    assert_eq!(cancelled["direction"], "incoming");
+}
+
+#[tokio::test]
+async fn receiver_can_cancel_transfer() {
+    let (alice, bob) = linked_pair().await;
+    let (sending, transfer_id) = held_transfer(&alice, &bob).await;
+
+    crate::cancel_transfer(
+        &bob.ctx,
+        &alice.name,
+        transfer_id,
+        crate::Direction::Incoming,
+    )
+    .await
+    .unwrap();
+    let res = sending.await.unwrap();
+    assert!(res.unwrap_err().contains("cancelled"));
+    let cancelled = alice.frontend.next_event(crate::TRANSFER_CANCELLED).await;
+    assert_eq!(cancelled["transfer_id"], transfer_id);
+    assert_eq!(cancelled["direction"], "outgoing");
+
+    let res = crate::cancel_transfer(
+        &bob.ctx,
+        &alice.name,
+        transfer_id,
+        crate::Direction::Incoming,
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(crate::NetworkError::CommunicationError(
+            CommunicationError::TransferNotFound
+        ))
+    ));
+
+    let res = crate::cancel_transfer(
+        &alice.ctx,
+        &bob.name,
+        transfer_id,
+        crate::Direction::Outgoing,
+    )
+    .await;
+    assert!(matches!(
+        res,
+        Err(crate::NetworkError::CommunicationError(
+            CommunicationError::TransferNotFound
+        ))
+    ));
+}
+
+/// Send `contents` from `alice` to `bob` in one chunk, written by hand so that the checksum of the
+/// chunk and the hash announced for the whole file can be made wrong. Returns the id of the
+/// transfer and where it stands on the side of `alice` once `bob` answered
+async fn send_tampered_file(
+    alice: &Peer,
+    bob: &Peer,
+    contents: &[u8],
+    chunk_checksum: blake3::Hash,
+    file_hash: blake3::Hash,
+) -> (u32, OutgoingState) {
+    let channel = crate::linked_channel(&alice.ctx, &bob.name).await.unwrap();
+    let transfer_id = crate::transfer::next_transfer_id();
+    let mut outgoing = channel.pending.register(transfer_id);
+    let prepare = protocol::PrepareFileTransfer {
+        file_name: "data.bin".to_string(),
+        size: contents.len() as u64,
+        assoc_text: None,
+        transfer_id,
+        hash: file_hash.as_bytes().to_vec(),
+        directory_id: None,
+    };
+    let encprepare = protocol::encode(TransferType::PrepareFileTransfer, prepare);
+    channel.outbox.send(encprepare).await.unwrap();
+    assert!(outgoing.response().await.unwrap().accepted);
+
+    let chunk = protocol::FileChunk {
+        transfer_id,
+        data: contents.to_vec(),
+        checksum: chunk_checksum.as_bytes().to_vec(),
+    };
+    let encchunk = protocol::encode_stream(TransferType::FileChunk, transfer_id, chunk);
+    channel.outbox.send(encchunk).await.unwrap();
+    let end = protocol::FileTransferEnd { transfer_id };
+    let encend = protocol::encode_stream(TransferType::FileTransferEnd, transfer_id, end);
+    channel.outbox.send(encend).await.unwrap();
+    alice.frontend.next_event(crate::TRANSFER_FAILED).await;
+    (transfer_id, outgoing.state())
+}
+
+#[tokio::test]
+async fn corrupted_chunk_fails_transfer() {
+    let (alice, bob) = linked_pair().await;
+    let contents = vec![7u8; 1_000];
+    let (transfer_id, state) = send_tampered_file(
+        &alice,
+        &bob,
+        &contents,
+        blake3::hash(b"something else"),
+        blake3::hash(&contents),
+    )
+    .await;
+
+    assert!(matches!(state, OutgoingState::Failed(_)));
+    let failed = bob.frontend.next_event(crate::TRANSFER_FAILED).await;
+    assert_eq!(failed["transfer_id"], transfer_id);
+    assert!(!bob.fdrop_dir().await.join("data.bin").exists());
+}
+
+#[tokio::test]
+async fn wrong_file_hash_fails_transfer() {
+    let (alice, bob) = linked_pair().await;
+    let contents = vec![7u8; 1_000];
+    let (transfer_id, state) = send_tampered_file(
+        &alice,
+        &bob,
+        &contents,
+        blake3::hash(&contents),
+        blake3::hash(b"something else"),
+    )
+    .await;
+
+    assert!(matches!(state, OutgoingState::Failed(_)));
+    let failed = bob.frontend.next_event(crate::TRANSFER_FAILED).await;
+    assert_eq!(failed["transfer_id"], transfer_id);
+    assert!(!bob.fdrop_dir().await.join("data.bin").exists());
+    let partial_path = crate::transfer::partial_path(
+        &bob.ctx.data_dir,
+        &bob.fdrop_dir().await.join("data.bin"),
+        contents.len() as u64,
+    );
+    assert!(!partial_path.exists());
+}
+
+#[tokio::test]
+async fn upload_limit_slows_down_sending() {
+    let (alice, bob) = linked_pair().await;
+    crate::set_device_upload_limit(&alice.ctx, &bob.name, Some(100_000))
+        .await
+        .unwrap();
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 50_000);
+
+    let started = std::time::Instant::now();
+    crate::send_files(&alice.ctx, &bob.name, vec![path], None)
+        .await
+        .unwrap();
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+    assert!(started.elapsed() >= Duration::from_millis(400));
+
+    let public_key = hex::encode(bob.keypair().await.public().to_bytes());
+    let device = fdrop_config::find_trusted_device(&alice.ctx.data_dir, &public_key).unwrap();
+    assert_eq!(device.upload_limit, Some(100_000));
+}
+
+#[tokio::test]
+async fn text_is_not_held_back_by_file() {
+    let (alice, bob) = linked_pair().await;
+    crate::set_device_upload_limit(&alice.ctx, &bob.name, Some(100_000))
+        .await
+        .unwrap();
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 200_000);
+
+    let ctx = alice.ctx.clone();
+    let name = bob.name.clone();
+    tokio::spawn(async move { crate::send_files(&ctx, &name, vec![path], None).await });
+    bob.frontend.next_event(crate::TRANSFER_PROGRESS).await;
+    crate::send_text_message(&alice.ctx, &bob.name, "still there?".to_string())
+        .await
+        .unwrap();
+    let transfer = bob.frontend.next_event(crate::TRANSFER).await;
+    assert_eq!(transfer["display_content"], "still there?");
+    assert!(!bob.fdrop_dir().await.join("data.bin").exists());
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+}
+
+#[tokio::test]
+async fn transfers_are_kept_in_history() {
+    let (alice, bob) = linked_pair().await;
+    crate::send_text_message(&alice.ctx, &bob.name, "hello bob".to_string())
+        .await
+        .unwrap();
+    bob.frontend.next_event(crate::TRANSFER).await;
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 20_000);
+    crate::send_files(&alice.ctx, &bob.name, vec![path.clone()], None)
+        .await
+        .unwrap();
+    bob.frontend.next_event(crate::TRANSFER_COMPLETE).await;
+
+    let sent = crate::transfer_history(&alice.ctx, &bob.name, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].kind, crate::TransferKind::File);
+    assert_eq!(sent[0].status, crate::TransferStatus::Completed);
+    assert_eq!(sent[0].path, Some(path));
+    assert_eq!(sent[1].content, "hello bob");
+
+    let received = crate::transfer_history(&bob.ctx, &alice.name, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].size, 20_000);
+    assert_eq!(
+        received[0].path,
+        Some(bob.fdrop_dir().await.join("data.bin"))
+    );
+    let older = crate::transfer_history(&bob.ctx, &alice.name, 1, 10)
+        .await
+        .unwrap();
+    assert_eq!(older.len(), 1);
+    assert_eq!(older[0].direction, crate::Direction::Incoming);
+    assert_eq!(older[0].content, "hello bob");
+}
+
+#[tokio::test]
+async fn file_the_peer_fails_to_store_is_recorded_as_failed() {
+    let (alice, bob) = linked_pair().await;
+    // Bob cannot move the received file in place of a directory with the same name
+    std::fs::create_dir_all(bob.fdrop_dir().await.join("data.bin").join("taken")).unwrap();
+    let path = alice.ctx.data_dir.join("outgoing").join("data.bin");
+    write_file(&path, 20_000);
+
+    let res = crate::send_files(&alice.ctx, &bob.name, vec![path], None).await;
+    assert!(res.is_err());
+    bob.frontend.next_event(crate::TRANSFER_FAILED).await;
+    let sent = crate::transfer_history(&alice.ctx, &bob.name, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].status, crate::TransferStatus::Failed);
+}
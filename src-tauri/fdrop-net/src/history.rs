@@ -0,0 +1,179 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::AsyncWriteExt;
+use tracing::{error, warn};
+
+use crate::{
+    errors::CommunicationError,
+    transfer::{Direction, TransferOffer},
+};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferKind {
+    Text,
+    File,
+    Directory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferStatus {
+    Completed,
+    Failed,
+    Cancelled,
+    Declined,
+}
+
+impl TransferStatus {
+    /// Status of a transfer that ended with `res`
+    pub(crate) fn of<T>(res: &Result<T, CommunicationError>) -> Self {
+        match res {
+            Ok(_) => Self::Completed,
+            Err(CommunicationError::TransferDeclined(_)) => Self::Declined,
+            Err(CommunicationError::TransferCancelled) => Self::Cancelled,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+/// A text message, file or directory that was sent to or received from a device
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    /// Name of the device on the other end of the transfer
+    pub device: String,
+    pub direction: Direction,
+    pub kind: TransferKind,
+    /// Seconds since the Unix epoch at which the transfer ended
+    pub timestamp: u64,
+    /// Contents of a text message or name of a file or directory
+    pub content: String,
+    pub assoc_text: Option<String>,
+    pub size: u64,
+    pub status: TransferStatus,
+    /// Location of the file or directory on this device
+    pub path: Option<PathBuf>,
+}
+
+impl HistoryEntry {
+    /// Entry for a transfer that ends now
+    pub(crate) fn new(
+        device: String,
+        direction: Direction,
+        kind: TransferKind,
+        content: String,
+        size: u64,
+        status: TransferStatus,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            device,
+            direction,
+            kind,
+            timestamp,
+            content,
+            assoc_text: None,
+            size,
+            status,
+            path: None,
+        }
+    }
+
+    /// Entry for a transfer offered by the peer that ended before anything was received
+    pub(crate) fn offer(offer: &TransferOffer, status: TransferStatus) -> Self {
+        let kind = if offer.directory {
+            TransferKind::Directory
+        } else {
+            TransferKind::File
+        };
+        Self {
+            assoc_text: offer.assoc_text.clone(),
+            ..Self::new(
+                offer.device.clone(),
+                Direction::Incoming,
+                kind,
+                offer.name.clone(),
+                offer.size,
+                status,
+            )
+        }
+    }
+}
+
+/// Transfers that have ended, kept in the data folder
+///
+/// Every transfer is appended to the log as a line of JSON once it ends. A line that was cut
+/// short by a crash is skipped when reading.
+#[derive(Clone, Debug)]
+pub(crate) struct History {
+    path: PathBuf,
+    /// Keeps entries recorded at the same time from being interleaved
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl History {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join(HISTORY_FILE),
+            lock: Arc::default(),
+        }
+    }
+
+    pub async fn record(&self, entry: HistoryEntry) {
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        let _guard = self.lock.lock().await;
+        let res = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(&line).await?;
+            file.flush().await
+        }
+        .await;
+        if let Err(e) = res {
+            error!(path = ?self.path, "failed to record transfer in history: {}", e);
+        }
+    }
+
+    /// Entries of the device `device` from the most recent one on, skipping the first `offset`
+    /// and returning at most `limit`
+    pub async fn page(
+        &self,
+        device: &str,
+        offset: usize,
+        limit: usize,
+    ) -> std::io::Result<Vec<HistoryEntry>> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let entries = contents
+            .lines()
+            .rev()
+            .filter_map(|line| match serde_json::from_str::<HistoryEntry>(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("skipping invalid history entry: {}", e);
+                    None
+                }
+            })
+            .filter(|entry| entry.device == device)
+            .skip(offset)
+            .take(limit)
+            .collect();
+        Ok(entries)
+    }
+}
@@ -1,62 +1,192 @@
+mod bandwidth;
+#[cfg(feature = "tauri")]
+pub mod commands;
 mod errors;
+mod frontend;
+#[cfg(test)]
+mod harness;
+mod history;
+mod mux;
 mod protocol;
+mod queue;
+mod secure;
 mod transfer;
 
+use bandwidth::Throttle;
 use bytes::{Bytes, BytesMut};
 use errors::{CommunicationError, DiscoveryError, NetworkError};
+use fdrop_common::human_readable_error;
 use fdrop_config::UserConfig;
-use flume::{bounded, Receiver, Sender};
+pub use frontend::{BoxFuture, Frontend};
+use history::History;
+pub use history::{HistoryEntry, TransferKind, TransferStatus};
+use libp2p::identity::ed25519;
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use mux::Outbox;
 use prost::Message;
-use protocol::{LinkResponse, TransferType};
+pub use protocol::LinkResponse;
+use protocol::TransferType;
+use queue::OfflineQueue;
+pub use queue::{Delivery, DeliveryState, QueuedContent, QueuedItem};
+use secure::SecureStream;
 use socket2::{Domain, Type};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     hash::Hash,
+    io::SeekFrom,
     net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
-use tauri::{AppHandle, Emitter, Listener, Manager, WebviewUrl};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     sync::Mutex,
+    task::JoinSet,
 };
-use tracing::{error, info};
-use transfer::{DisplayContent, DisplayFileTransfer, Transfer};
+use tracing::{error, info, warn};
+use transfer::{
+    ActiveTransfers, Control, DirectoryProgress, DisplayContent, DisplayFileTransfer, Incoming,
+    IncomingDirectory, IncomingFile, Offer, OutgoingState, OutgoingTransfer, PendingTransfers,
+    PolicyDecision, ProgressTracker, Transfer, TransferCancelled, TransferComplete, TransferFailed,
+};
+pub use transfer::{Direction, MessageReceipt, MessageStatus, TransferOffer, TransferProgress};
 
 const MDNS_SERVICE_TYPE: &str = "_fdrop._tcp.local.";
-const FDROP_PORT: u16 = 10116;
-const DEVICE_DISCOVERED: &str = "device-discovered";
-const DEVICE_REMOVED: &str = "device-removed";
-const LINK_RESPONSE: &str = "link-response";
-const DEVICE_LINKED: &str = "device-linked";
-const MAX_PAYLOAD_SIZE: usize = 2048;
+/// TXT record property in which the hex encoded identity key is advertised
+const PUBLIC_KEY_PROPERTY: &str = "pk";
+pub const DEVICE_DISCOVERED: &str = "device-discovered";
+pub const DEVICE_REMOVED: &str = "device-removed";
+pub const DEVICE_LINKED: &str = "device-linked";
+pub const DEVICE_CONNECTING: &str = "device-connecting";
+pub const LINK_VERIFICATION_CODE: &str = "link-verification-code";
+pub const TRANSFER: &str = "transfer";
+pub const TRANSFER_COMPLETE: &str = "transfer-complete";
+pub const TRANSFER_FAILED: &str = "transfer-failed";
+pub const DIRECTORY_PROGRESS: &str = "directory-progress";
+pub const TRANSFER_PROGRESS: &str = "transfer-progress";
+pub const TRANSFER_CANCELLED: &str = "transfer-cancelled";
+pub const MESSAGE_RECEIPT: &str = "message-receipt";
+pub const DEVICE_DISCONNECTED: &str = "device-disconnected";
+pub const DEVICE_UNLINKED: &str = "device-unlinked";
+pub const DELIVERY: &str = "delivery";
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024;
+/// Number of file bytes carried by a single `FileChunk`. Kept well below `MAX_PAYLOAD_SIZE` to
+/// leave room for the protobuf overhead
+const FILE_CHUNK_SIZE: usize = 8 * 1024;
+/// How often a linked device is pinged
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a linked device can stay silent before the stream to it is considered dead
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long connecting to an address of a device may take before the next one is tried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times a device that cannot be reached is tried before giving up on linking
+const LINK_ATTEMPTS: u32 = 5;
+/// Time to wait before trying to reach a device again. Doubles with every attempt
+const LINK_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 #[cfg(target_os = "linux")]
-static OUR_PLATFORM: &'static str = "linux";
+static OUR_PLATFORM: &str = "linux";
 #[cfg(target_os = "windows")]
-static OUR_PLATFORM: &'static str = "windows";
+static OUR_PLATFORM: &str = "windows";
 #[cfg(target_os = "macos")]
-static OUR_PLATFORM: &'static str = "macos";
+static OUR_PLATFORM: &str = "macos";
 #[cfg(target_os = "android")]
-static OUR_PLATFORM: &'static str = "android";
+static OUR_PLATFORM: &str = "android";
 #[cfg(target_os = "ios")]
-static OUR_PLATFORM: &'static str = "ios";
+static OUR_PLATFORM: &str = "ios";
 
 #[derive(Debug)]
 pub struct Connection {
     pub info: ConnectionInfo,
     addresses: Vec<IpAddr>,
-    tx: Option<Sender<Bytes>>,
+    /// Port on which the device accepts connections
+    port: u16,
+    outbox: Option<Outbox>,
+    pending: PendingTransfers,
+    /// Identity key of the device. Initially this is the key advertised over mDNS and once linked
+    /// it is the key the device proved ownership of
+    public_key: Option<ed25519::PublicKey>,
+    /// The device was added by address and is known under a placeholder name until it is linked
+    added_by_address: bool,
 }
 
 #[derive(Debug, serde::Serialize, Clone)]
 pub struct ConnectionInfo {
     pub name: String,
     pub linked: bool,
+    pub state: ConnectionState,
     pub platform: Option<String>,
 }
 
+/// Whether there is a stream to a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Offline,
+    /// A link request is being sent to the device
+    Connecting,
+    Linked,
+}
+
+impl ConnectionInfo {
+    fn set_state(&mut self, state: ConnectionState) {
+        self.state = state;
+        self.linked = state == ConnectionState::Linked;
+    }
+}
+
+/// Verification code for a link request being sent to the device `name`
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct VerificationCode {
+    pub name: String,
+    pub code: String,
+}
+
+/// State shared by all the tasks of the networking core
+#[derive(Clone)]
+pub struct NetworkContext {
+    pub connection_manager: Arc<Mutex<ConnectionManager>>,
+    pub config: Arc<Mutex<UserConfig>>,
+    /// Folder where the identity and the trusted devices are stored
+    pub data_dir: PathBuf,
+    pub frontend: Arc<dyn Frontend>,
+    transfers: ActiveTransfers,
+    /// Limits the upload rate to all devices together
+    upload_limit: Throttle,
+    history: History,
+    /// Items waiting for offline devices
+    queue: OfflineQueue,
+}
+
+impl NetworkContext {
+    pub fn new(
+        connection_manager: Arc<Mutex<ConnectionManager>>,
+        config: UserConfig,
+        data_dir: PathBuf,
+        frontend: impl Frontend,
+    ) -> Self {
+        Self {
+            connection_manager,
+            upload_limit: Throttle::new(config.upload_limit),
+            config: Arc::new(Mutex::new(config)),
+            history: History::new(&data_dir),
+            queue: OfflineQueue::new(&data_dir),
+            data_dir,
+            frontend: Arc::new(frontend),
+            transfers: ActiveTransfers::default(),
+        }
+    }
+
+    fn emit(&self, event: &str, payload: impl serde::Serialize) {
+        self.frontend
+            .emit(event, serde_json::to_value(payload).unwrap());
+    }
+}
+
 impl PartialEq for Connection {
     fn eq(&self, other: &Self) -> bool {
         self.info.name == other.info.name
@@ -79,113 +209,228 @@ impl From<&ServiceInfo> for Connection {
             // TODO: Get proper name
             name: value.get_fullname().to_string(),
             linked: false,
+            state: ConnectionState::Offline,
             platform: None,
         };
+        let public_key = value
+            .get_property_val_str(PUBLIC_KEY_PROPERTY)
+            .and_then(|pk| hex::decode(pk).ok())
+            .and_then(|pk| ed25519::PublicKey::try_from_bytes(&pk).ok());
         Connection {
             info,
-            addresses: value.get_addresses().iter().map(|i| *i).collect(),
-            tx: None,
+            addresses: value.get_addresses().iter().copied().collect(),
+            port: value.get_port(),
+            outbox: None,
+            pending: PendingTransfers::default(),
+            public_key,
+            added_by_address: false,
         }
     }
 }
 
 impl Connection {
-    #[tracing::instrument(skip(handle))]
-    async fn send_link_request(
-        &mut self,
-        handle: AppHandle,
-        our_name: &str,
-    ) -> Result<LinkResponse, CommunicationError> {
-        if self.tx.is_some() {
-            return Ok(LinkResponse::Accepted);
+    /// Create a device that is reachable at `addresses` and `port` but whose identity is not
+    /// known yet
+    fn new(name: String, addresses: Vec<IpAddr>, port: u16) -> Self {
+        Connection {
+            info: ConnectionInfo {
+                name,
+                linked: false,
+                state: ConnectionState::Offline,
+                platform: None,
+            },
+            addresses,
+            port,
+            outbox: None,
+            pending: PendingTransfers::default(),
+            public_key: None,
+            added_by_address: false,
         }
-        for addr in &self.addresses {
-            let stream = TcpStream::connect(SocketAddr::new(addr.clone(), FDROP_PORT)).await;
-            if let Ok(mut sock) = stream {
-                let message = protocol::protobuf::Link {
-                    request: Some(true),
-                    name: our_name.to_string(),
-                    platform: String::from(OUR_PLATFORM),
-                    response: None,
-                };
-                let auth_message = protocol::encode(TransferType::Link, message);
-                info!("sending link request to address {}", addr);
-                sock.write_all(&auth_message)
-                    .await
-                    .map_err(|e| CommunicationError::WriteError(e))?;
-                let (ttype, mut payload) = read_stream(&mut sock).await.unwrap();
-                if TransferType::try_from(ttype).unwrap() != TransferType::Link {
-                    error!("link request received invalid response type from peer. rejecting peer");
-                }
-                let resp = protocol::Link::decode(&mut payload)
-                    .map_err(|_| CommunicationError::DecodeError)?;
-                if matches!(
-                    LinkResponse::try_from(resp.response.unwrap()).unwrap(),
-                    protocol::LinkResponse::Rejected | protocol::LinkResponse::Other
-                ) {
-                    info!("the peer rejected the link request");
-                    return Ok(LinkResponse::Rejected);
-                }
-                let (tx, rx) = flume::bounded(100);
-                self.tx = Some(tx);
-                self.info.platform = Some(resp.platform);
-                tokio::spawn(async move {
-                    handle_postauth_stream(sock, rx, handle).await;
-                });
-                info!("successfully linked with peer");
-                return Ok(LinkResponse::Accepted);
+    }
+
+    /// Where and how to reach the device, to link with it while the connection manager is not
+    /// locked
+    fn link_target(&self) -> LinkTarget {
+        LinkTarget {
+            name: self.info.name.clone(),
+            addresses: self.addresses.clone(),
+            port: self.port,
+            public_key: self.public_key.clone(),
+        }
+    }
+}
+
+/// Copy of what is needed to link with a [`Connection`]
+struct LinkTarget {
+    name: String,
+    addresses: Vec<IpAddr>,
+    port: u16,
+    public_key: Option<ed25519::PublicKey>,
+}
+
+/// A device that accepted a link request
+struct Linked {
+    stream: SecureStream,
+    peer_key: ed25519::PublicKey,
+    /// Instance name the device gave for itself
+    name: String,
+    platform: String,
+}
+
+/// Link with the device `target`. While it cannot be reached, it is tried again with exponentially
+/// growing delays, as a device that was just rediscovered may not accept connections yet.
+/// Returns the answer of the device when it did not accept the request
+#[tracing::instrument(skip_all, fields(device = target.name))]
+async fn send_link_request(
+    ctx: &NetworkContext,
+    target: &LinkTarget,
+    our_name: &str,
+    keypair: &ed25519::Keypair,
+    listen_port: Option<u16>,
+) -> Result<Result<Linked, LinkResponse>, CommunicationError> {
+    let mut delay = LINK_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let res = try_link_request(ctx, target, our_name, keypair, listen_port).await;
+        match res {
+            Err(
+                ref e @ (CommunicationError::NoReachableAddress
+                | CommunicationError::ReadError(_)
+                | CommunicationError::WriteError(_)
+                | CommunicationError::Io(_)),
+            ) if attempt < LINK_ATTEMPTS => {
+                warn!(
+                    attempt,
+                    "failed to reach peer, trying again in {:?}: {}", delay, e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+async fn try_link_request(
+    ctx: &NetworkContext,
+    target: &LinkTarget,
+    our_name: &str,
+    keypair: &ed25519::Keypair,
+    listen_port: Option<u16>,
+) -> Result<Result<Linked, LinkResponse>, CommunicationError> {
+    for addr in &target.addresses {
+        let address = SocketAddr::new(*addr, target.port);
+        let Ok(Ok(sock)) = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await
+        else {
+            continue;
+        };
+        let mut sock =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, secure::handshake(sock, keypair, true))
+                .await
+                .map_err(|_| CommunicationError::HandshakeError)??;
+        let code = VerificationCode {
+            name: target.name.clone(),
+            code: sock.verification_code(),
+        };
+        ctx.emit(LINK_VERIFICATION_CODE, code);
+        let message = protocol::protobuf::Link {
+            request: Some(true),
+            name: our_name.to_string(),
+            platform: String::from(OUR_PLATFORM),
+            response: None,
+            public_key: keypair.public().to_bytes().to_vec(),
+            signature: sock.sign_link(keypair),
+            port: listen_port.map(u32::from),
+        };
+        let auth_message = protocol::encode(TransferType::Link, message);
+        info!("sending link request to address {}", addr);
+        sock.write_message(&auth_message).await?;
+        let (ttype, mut payload) = sock.read_message().await?;
+        if ttype != TransferType::Link {
+            error!("link request received invalid response type from peer. rejecting peer");
+            return Err(CommunicationError::Unauthenticated);
+        }
+        let resp =
+            protocol::Link::decode(&mut payload).map_err(|_| CommunicationError::DecodeError)?;
+        let peer_key = sock.verify_link(&resp.public_key, &resp.signature)?;
+        if target.public_key.as_ref().is_some_and(|pk| *pk != peer_key) {
+            error!("peer does not own the identity it advertised");
+            return Err(CommunicationError::IdentityMismatch);
+        }
+        let response = resp
+            .response
+            .and_then(|r| LinkResponse::try_from(r).ok())
+            .ok_or(CommunicationError::DecodeError)?;
+        match response {
+            LinkResponse::Accepted => {}
+            LinkResponse::CodeMismatch => {
+                warn!(
+                    "verification codes did not match. the connection may have been tampered with"
+                );
+                return Ok(Err(LinkResponse::CodeMismatch));
+            }
+            LinkResponse::Rejected | LinkResponse::Other | LinkResponse::Blocked => {
+                info!("the peer rejected the link request");
+                return Ok(Err(LinkResponse::Rejected));
             }
         }
-        Err(CommunicationError::NoReachableAddress)
+        return Ok(Ok(Linked {
+            stream: sock,
+            peer_key,
+            name: resp.name,
+            platform: resp.platform,
+        }));
     }
+    Err(CommunicationError::NoReachableAddress)
 }
 
 pub struct ConnectionManager {
     mdns_daemon: ServiceDaemon,
     available_connections: HashMap<String, Connection>,
     instance_name: Option<String>,
-    active_link_requests: u8,
+    keypair: Option<ed25519::Keypair>,
+    /// Port on which this device accepts connections
+    listen_port: Option<u16>,
 }
 
 impl ConnectionManager {
     pub fn new() -> Result<Mutex<Self>, NetworkError> {
-        let mdns = ServiceDaemon::new().map_err(|e| DiscoveryError::ServiceDaemonError(e))?;
+        let mdns = ServiceDaemon::new().map_err(DiscoveryError::ServiceDaemonError)?;
         mdns.set_multicast_loop_v4(false)
-            .map_err(|e| DiscoveryError::ServiceDaemonError(e))?;
+            .map_err(DiscoveryError::ServiceDaemonError)?;
         mdns.set_multicast_loop_v6(false)
-            .map_err(|e| DiscoveryError::ServiceDaemonError(e))?;
+            .map_err(DiscoveryError::ServiceDaemonError)?;
         Ok(Mutex::new(Self {
             mdns_daemon: mdns,
             available_connections: HashMap::new(),
             instance_name: None,
-            active_link_requests: 0,
+            keypair: None,
+            listen_port: None,
         }))
     }
 
     pub fn shutdown(&self) -> Result<(), DiscoveryError> {
         self.mdns_daemon
             .stop_browse(MDNS_SERVICE_TYPE)
-            .map_err(|e| DiscoveryError::ShutdownError(e))?;
+            .map_err(DiscoveryError::ShutdownError)?;
         if let Some(ref name) = self.instance_name {
             self.mdns_daemon
                 .unregister(name)
-                .map_err(|e| DiscoveryError::ShutdownError(e))?;
+                .map_err(DiscoveryError::ShutdownError)?;
         }
         self.mdns_daemon
             .shutdown()
-            .map_err(|e| DiscoveryError::ShutdownError(e))?;
+            .map_err(DiscoveryError::ShutdownError)?;
         info!("closed mdns service daemon");
         Ok(())
     }
 
-    pub fn get_connectionss<'a>(&'a self) -> impl Iterator<Item = &'a ConnectionInfo> {
-        self.available_connections
-            .values()
-            .into_iter()
-            .map(|c| &c.info)
+    pub fn get_connectionss(&self) -> impl Iterator<Item = &ConnectionInfo> {
+        self.available_connections.values().map(|c| &c.info)
     }
 
-    pub fn get_connection(&mut self, name: &str) -> Option<&Connection> {
+    pub fn get_connection(&self, name: &str) -> Option<&Connection> {
         self.available_connections.get(name)
     }
 
@@ -193,43 +438,164 @@ impl ConnectionManager {
         self.available_connections.get_mut(name)
     }
 
-    pub(crate) fn connection_exists(&mut self, name: &str) -> bool {
+    pub(crate) fn connection_exists(&self, name: &str) -> bool {
         self.available_connections.contains_key(name)
     }
 }
 
-async fn accept_connections(handle: AppHandle) -> Result<(), CommunicationError> {
-    let socket = socket2::Socket::new(Domain::IPV6, Type::STREAM, None)?;
-    socket.set_only_v6(false)?;
-    let address = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, FDROP_PORT, 0, 0);
-    socket.bind(&address.into())?;
+/// Load the identity, advertise this device over mDNS and start accepting link requests.
+/// Returns once the background tasks have been started
+pub async fn start_networking(ctx: &NetworkContext) -> Result<(), NetworkError> {
+    let keypair = fdrop_config::read_keys_from(&ctx.data_dir)?;
+    let (bind_address, port, static_peers) = {
+        let user_config = ctx.config.lock().await;
+        (
+            user_config.bind_address,
+            user_config.port,
+            user_config.static_peers.clone(),
+        )
+    };
+    let listener = bind_listener(bind_address, port)?;
+    // The system picks the port when it is configured as 0, so advertise the one actually bound
+    let port = listener
+        .local_addr()
+        .map_err(CommunicationError::Io)?
+        .port();
+    {
+        let mut connection_manager = ctx.connection_manager.lock().await;
+        connection_manager.keypair = Some(keypair);
+        connection_manager.listen_port = Some(port);
+    }
+    launch_discovery_service(ctx.clone(), port).await?;
+    accept_connections(ctx.clone(), listener).await?;
+    for peer in static_peers {
+        if let Err(e) = add_peer(ctx, &peer.host, peer.port, false).await {
+            warn!(host = %peer.host, "failed to add static peer: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Make the device at `host` and `port` available for linking, as if it had been discovered.
+/// The device is known as `host:port` until it is linked. When `remember` is set, the device is
+/// also saved as a static peer and added on every start
+pub async fn add_peer(
+    ctx: &NetworkContext,
+    host: &str,
+    port: u16,
+    remember: bool,
+) -> Result<ConnectionInfo, NetworkError> {
+    let addresses: Vec<IpAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(CommunicationError::AddressResolution)?
+        .map(|a| a.ip())
+        .collect();
+    if addresses.is_empty() {
+        return Err(CommunicationError::NoReachableAddress.into());
+    }
+
+    if remember {
+        let mut user_config = ctx.config.lock().await;
+        let peer = fdrop_config::StaticPeer {
+            host: host.to_string(),
+            port,
+        };
+        if !user_config.static_peers.contains(&peer) {
+            user_config.static_peers.push(peer);
+            fdrop_config::save_config(&ctx.data_dir, &user_config)?;
+        }
+    }
+
+    let name = format!("{host}:{port}");
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    if let Some(con) = connection_manager.get_connection(&name) {
+        return Ok(con.info.clone());
+    }
+    info!(name, "adding peer by address");
+    let con = Connection {
+        added_by_address: true,
+        ..Connection::new(name.clone(), addresses, port)
+    };
+    let info = con.info.clone();
+    ctx.emit(DEVICE_DISCOVERED, &info);
+    connection_manager.available_connections.insert(name, con);
+    Ok(info)
+}
+
+/// Create the listener on which peers connect to this device
+fn bind_listener(
+    bind_address: Option<IpAddr>,
+    port: u16,
+) -> Result<TcpListener, CommunicationError> {
+    let socket = match bind_address {
+        Some(ip) => {
+            let address = SocketAddr::new(ip, port);
+            let socket = socket2::Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+            socket.bind(&address.into())?;
+            socket
+        }
+        // Accept both IPv4 and IPv6 connections on a single socket
+        None => {
+            let socket = socket2::Socket::new(Domain::IPV6, Type::STREAM, None)?;
+            socket.set_only_v6(false)?;
+            let address = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+            socket.bind(&address.into())?;
+            socket
+        }
+    };
     socket.listen(128)?;
+    socket.set_nonblocking(true)?;
     let std_listener: std::net::TcpListener = socket.into();
     let listener: TcpListener = TcpListener::from_std(std_listener)?;
     info!("created the connection acceptor");
+    Ok(listener)
+}
+
+async fn accept_connections(
+    ctx: NetworkContext,
+    listener: TcpListener,
+) -> Result<(), CommunicationError> {
+    let keypair = {
+        let connection_manager = ctx.connection_manager.lock().await;
+        connection_manager.keypair.clone().unwrap()
+    };
 
     tokio::spawn(async move {
         loop {
             let conn = listener.accept().await;
             match conn {
-                Ok((mut stream, _)) => {
-                    let handle2 = handle.clone();
+                Ok((stream, peer_address)) => {
+                    let ctx2 = ctx.clone();
+                    let keypair = keypair.clone();
                     tokio::spawn(async move {
                         info!("eshtablished stream with peer");
-                        let ret = authenticate_peer(&mut stream, &handle2).await;
-                        if let Ok(Some((rx, full_name))) = ret {
+                        let handshake = secure::handshake(stream, &keypair, false);
+                        let mut stream =
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    error!("failed to establish secure channel with peer: {e}");
+                                    return;
+                                }
+                                Err(_) => {
+                                    error!("peer did not complete the handshake in time");
+                                    return;
+                                }
+                            };
+                        let ret = authenticate_peer(&mut stream, peer_address, &ctx2).await;
+                        if let Ok(Some((outbox, full_name))) = ret {
                             // HACK: Sleep for some time prevents the subsequent emit call to not hang and crash the
                             // entire app
                             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                            {
-                                let cm_lock = handle2.state::<Mutex<ConnectionManager>>();
-                                let mut connection_manager = cm_lock.lock().await;
+                            let pending = {
+                                let mut connection_manager = ctx2.connection_manager.lock().await;
                                 let con =
                                     connection_manager.get_connection_mut(&full_name).unwrap();
-                                handle2.emit(DEVICE_LINKED, &con.info).unwrap();
-                            }
+                                ctx2.emit(DEVICE_LINKED, &con.info);
+                                con.pending.clone()
+                            };
                             info!("sending control of stream to post auth handler");
-                            handle_postauth_stream(stream, rx, handle2).await;
+                            handle_postauth_stream(stream, outbox, pending, full_name, ctx2).await;
                         } else {
                             info!("rejecting peer");
                         }
@@ -240,40 +606,47 @@ async fn accept_connections(handle: AppHandle) -> Result<(), CommunicationError>
                 Err(e) => error!("failed to connect to peer due to {e}"),
             }
         }
-    })
-    .await
-    .unwrap();
+    });
     Ok(())
 }
 
-async fn launch_discovery_service(handle: AppHandle) -> Result<(), DiscoveryError> {
-    let hs = whoami::fallible::hostname().map_err(|e| DiscoveryError::HostnameError(e))?;
+async fn launch_discovery_service(ctx: NetworkContext, port: u16) -> Result<(), DiscoveryError> {
+    let hs = whoami::fallible::hostname().map_err(DiscoveryError::HostnameError)?;
     let local_hostname = format!("{}.local.", hs);
 
-    let user_details_lock = handle.state::<Mutex<UserConfig>>();
-    let user_details = user_details_lock.lock().await;
-    let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-    let mut connection_manager = cm_lock.lock().await;
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let user_details = ctx.config.lock().await;
+    let public_key = connection_manager
+        .keypair
+        .as_ref()
+        .map(|k| hex::encode(k.public().to_bytes()))
+        .unwrap_or_default();
+    let properties = HashMap::from([(PUBLIC_KEY_PROPERTY.to_string(), public_key)]);
+    // When bound to a single address, only that address can be reached
+    let bind_address = user_details.bind_address.filter(|ip| !ip.is_unspecified());
+    let host_ip = bind_address.map(|ip| ip.to_string()).unwrap_or_default();
 
-    let service = ServiceInfo::new(
+    let mut service = ServiceInfo::new(
         MDNS_SERVICE_TYPE,
         &user_details.instance_name,
         &local_hostname,
-        "",
-        FDROP_PORT,
-        None,
+        host_ip.as_str(),
+        port,
+        properties,
     )
-    .map_err(|e| DiscoveryError::ServiceError(e))?
-    .enable_addr_auto();
+    .map_err(DiscoveryError::ServiceError)?;
+    if bind_address.is_none() {
+        service = service.enable_addr_auto();
+    }
     connection_manager.instance_name = Some(service.get_fullname().to_string());
     connection_manager
         .mdns_daemon
         .register(service)
-        .map_err(|e| DiscoveryError::ServiceDaemonError(e))?;
+        .map_err(DiscoveryError::ServiceDaemonError)?;
     let receiver = connection_manager
         .mdns_daemon
         .browse(MDNS_SERVICE_TYPE)
-        .map_err(|e| DiscoveryError::BrowseError(e))?;
+        .map_err(DiscoveryError::BrowseError)?;
     info!("successfully created mdns service daemon");
     drop(connection_manager);
     drop(user_details);
@@ -285,24 +658,17 @@ async fn launch_discovery_service(handle: AppHandle) -> Result<(), DiscoveryErro
                     if info.get_hostname() == local_hostname {
                         continue;
                     }
-                    let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-                    let mut connection_manager = cm_lock.lock().await;
-                    let con = Connection::from(&info);
-                    handle.emit(DEVICE_DISCOVERED, &con.info)?;
-                    connection_manager
-                        .available_connections
-                        .insert(con.info.name.clone(), con);
                     info!("found device with name: {}", info.get_fullname());
+                    device_resolved(&ctx, Connection::from(&info)).await;
                 }
                 ServiceEvent::ServiceRemoved(_, name) => {
-                    let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-                    let mut connection_manager = cm_lock.lock().await;
-                    {
-                        let con = connection_manager.get_connection_mut(&name).unwrap();
-                        handle.emit(DEVICE_REMOVED, &con.info)?;
-                        info!("'{}' left", con.info.name);
-                    }
-                    connection_manager.available_connections.remove(&name);
+                    let mut connection_manager = ctx.connection_manager.lock().await;
+                    // mDNS may report a device as gone more than once
+                    let Some(con) = connection_manager.available_connections.remove(&name) else {
+                        continue;
+                    };
+                    ctx.emit(DEVICE_REMOVED, &con.info);
+                    info!("'{}' left", con.info.name);
                 }
                 ServiceEvent::SearchStopped(ss) if ss == MDNS_SERVICE_TYPE => {
                     break;
@@ -318,35 +684,105 @@ async fn launch_discovery_service(handle: AppHandle) -> Result<(), DiscoveryErro
     Ok(())
 }
 
-async fn read_stream(stream: &mut TcpStream) -> Result<(TransferType, Bytes), CommunicationError> {
+/// Record the device `discovered` over mDNS. A trusted device that is offline is linked with again
+async fn device_resolved(ctx: &NetworkContext, discovered: Connection) {
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let keypair = connection_manager.keypair.clone();
+    let name = discovered.info.name.clone();
+    // A device is resolved again when it comes back to the network or its addresses change. It
+    // keeps its stream and the identity it proved
+    let con = match connection_manager.available_connections.entry(name.clone()) {
+        Entry::Occupied(entry) => {
+            let con = entry.into_mut();
+            con.addresses = discovered.addresses;
+            con.port = discovered.port;
+            if con.public_key.is_none() {
+                con.public_key = discovered.public_key;
+            }
+            con
+        }
+        Entry::Vacant(entry) => entry.insert(discovered),
+    };
+    ctx.emit(DEVICE_DISCOVERED, &con.info);
+    let relink =
+        con.info.state == ConnectionState::Offline && should_relink(ctx, keypair.as_ref(), con);
+    drop(connection_manager);
+
+    if relink {
+        info!("re-establishing link with trusted device '{}'", name);
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = link_device(&ctx, &name).await {
+                error!("failed to link with trusted device: {}", e);
+            }
+        });
+    }
+}
+
+/// Name under which the device with the instance name `name` is known
+pub(crate) fn full_name(name: &str) -> String {
+    if name.ends_with(MDNS_SERVICE_TYPE) {
+        name.to_string()
+    } else {
+        name.to_string() + "." + MDNS_SERVICE_TYPE
+    }
+}
+
+/// Whether a link with a newly discovered device should be established without user interaction.
+/// This is the case for trusted devices. To avoid both devices connecting to each other at the
+/// same time, only the device with the smaller identity key sends the link request
+fn should_relink(
+    ctx: &NetworkContext,
+    keypair: Option<&ed25519::Keypair>,
+    con: &Connection,
+) -> bool {
+    let (Some(their_key), Some(keypair)) = (&con.public_key, keypair) else {
+        return false;
+    };
+    let their_key = their_key.to_bytes();
+    keypair.public().to_bytes() < their_key
+        && fdrop_config::is_trusted_device(&ctx.data_dir, &hex::encode(their_key))
+}
+
+async fn read_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<(TransferType, Bytes), CommunicationError> {
     let ttype_u8 = stream
         .read_u8()
         .await
-        .map_err(|e| CommunicationError::ReadError(e))?;
+        .map_err(CommunicationError::ReadError)?;
     let ttype = TransferType::try_from(ttype_u8)?;
+    // Messages name the transfer they belong to themselves. The stream only decides the order in
+    // which the peer writes them
+    let _stream = stream
+        .read_u32()
+        .await
+        .map_err(CommunicationError::ReadError)?;
     let payload_size = stream
         .read_u16()
         .await
-        .map_err(|e| CommunicationError::ReadError(e))?;
-    if payload_size > (MAX_PAYLOAD_SIZE as u16) {
-        // TODO: Return a response with invalid payload error
-        todo!();
+        .map_err(CommunicationError::ReadError)?;
+    // The stream cannot be trusted to be in sync anymore, so the caller closes it
+    if usize::from(payload_size) > MAX_PAYLOAD_SIZE {
+        error!(payload_size, "peer sent a message larger than allowed");
+        return Err(CommunicationError::DecodeError);
     }
     let mut payload = BytesMut::zeroed((payload_size).into());
     stream
         .read_exact(&mut payload)
         .await
-        .map_err(|e| CommunicationError::ReadError(e))?;
+        .map_err(CommunicationError::ReadError)?;
     Ok((ttype, payload.freeze()))
 }
 
 async fn authenticate_peer(
-    stream: &mut TcpStream,
-    handle: &AppHandle,
-) -> Result<Option<(Receiver<Bytes>, String)>, CommunicationError> {
+    stream: &mut SecureStream,
+    peer_address: SocketAddr,
+    ctx: &NetworkContext,
+) -> Result<Option<(Outbox, String)>, CommunicationError> {
     info!("authenticating new peer");
     info!("reading inital message");
-    let (mtype, payload) = read_stream(stream).await?;
+    let (mtype, payload) = stream.read_message().await?;
     if mtype != TransferType::Link {
         error!("peer sent unexpected messages before linking");
         return Err(CommunicationError::Unauthenticated);
@@ -358,37 +794,95 @@ async fn authenticate_peer(
         return Err(CommunicationError::DecodeError);
     }
     let link_req = link_req.unwrap();
+    let peer_key = stream.verify_link(&link_req.public_key, &link_req.signature)?;
 
-    let full_name = link_req.name.clone() + "." + MDNS_SERVICE_TYPE;
+    let full_name = full_name(&link_req.name);
     info!(
         "received link request from peer '{}'. authenticating",
         link_req.name
     );
 
-    let (our_name, win_label) = {
-        let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-        let mut connection_manager = cm_lock.lock().await;
+    let (our_name, keypair, listen_port) = {
+        let connection_manager = ctx.connection_manager.lock().await;
         let our_name = connection_manager.instance_name.clone().unwrap();
-        let win_label = "respond-link-request-".to_string()
-            + &connection_manager.active_link_requests.to_string();
-        connection_manager.active_link_requests += 1;
+        let keypair = connection_manager.keypair.clone().unwrap();
 
-        if !connection_manager.connection_exists(&full_name) {
-            return Err(CommunicationError::PeerNotFound);
+        // A device is bound to the key it advertised or linked with. Anyone else using its name
+        // is an impersonator
+        if let Some(con) = connection_manager.available_connections.get(&full_name) {
+            if con.public_key.as_ref().is_some_and(|pk| *pk != peer_key) {
+                error!("peer does not own the identity of the device it claims to be");
+                return Err(CommunicationError::IdentityMismatch);
+            }
         }
-        (our_name, win_label)
+        (our_name, keypair, connection_manager.listen_port)
     };
 
-    let resp = confirm_link_request(handle, &link_req.name, &win_label).await;
+    let peer_key_hex = hex::encode(peer_key.to_bytes());
+    let blocked = ctx
+        .config
+        .lock()
+        .await
+        .blocked
+        .iter()
+        .any(|b| b.matches(&peer_key_hex, &link_req.name));
+    let resp = if blocked {
+        // Refused without asking, so that a blocked device cannot keep bringing up prompts
+        info!("peer is blocked. rejecting link request");
+        LinkResponse::Rejected
+    } else if fdrop_config::is_trusted_device(&ctx.data_dir, &peer_key_hex) {
+        info!("peer is a trusted device. accepting link request");
+        LinkResponse::Accepted
+    } else {
+        let code = stream.verification_code();
+        let resp = ctx
+            .frontend
+            .confirm_link_request(&link_req.name, &code)
+            .await;
+        info!("user selected: {:?}", resp);
+        if resp == LinkResponse::Blocked {
+            block_device(ctx, &link_req.name, &peer_key_hex).await;
+            LinkResponse::Rejected
+        } else {
+            resp
+        }
+    };
 
     let ret = if resp == LinkResponse::Accepted {
-        let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-        let mut connection_manager = cm_lock.lock().await;
+        let mut connection_manager = ctx.connection_manager.lock().await;
+        // The peer may have been added by address on a network where it cannot be discovered.
+        // Reach it back at the address it connected from
+        if !connection_manager.connection_exists(&full_name) {
+            info!("registering peer that was not discovered");
+            let port = link_req
+                .port
+                .and_then(|p| u16::try_from(p).ok())
+                .unwrap_or(fdrop_config::DEFAULT_PORT);
+            let con = Connection::new(
+                full_name.clone(),
+                vec![peer_address.ip().to_canonical()],
+                port,
+            );
+            ctx.emit(DEVICE_DISCOVERED, &con.info);
+            connection_manager
+                .available_connections
+                .insert(full_name.clone(), con);
+        }
         let con = connection_manager.get_connection_mut(&full_name).unwrap();
-        let (tx, rx) = bounded(100);
-        con.tx = Some(tx);
+        let outbox = Outbox::default();
+        con.outbox = Some(outbox.clone());
+        con.info.set_state(ConnectionState::Linked);
         con.info.platform = Some(link_req.platform);
-        Ok(Some((rx, full_name)))
+        con.public_key = Some(peer_key);
+        if let Err(e) = fdrop_config::trust_device(
+            &ctx.data_dir,
+            &peer_key_hex,
+            &link_req.name,
+            con.info.platform.clone(),
+        ) {
+            error!("failed to save linked device: {}", e);
+        }
+        Ok(Some((outbox, full_name)))
     } else {
         Ok(None)
     };
@@ -398,261 +892,2152 @@ async fn authenticate_peer(
         name: our_name,
         response: Some(resp as i32),
         platform: String::from(OUR_PLATFORM),
+        public_key: keypair.public().to_bytes().to_vec(),
+        signature: stream.sign_link(&keypair),
+        port: listen_port.map(u32::from),
     };
 
     let resp_message = protocol::encode(TransferType::Link, resp);
-    stream.write_all(&resp_message).await.unwrap();
+    stream.write_message(&resp_message).await?;
     ret
 }
 
-/// Create confirmation window for a link request
-#[tracing::instrument(skip(handle))]
-async fn confirm_link_request(
-    handle: &AppHandle,
-    their_name: &str,
-    win_label: &str,
-) -> LinkResponse {
-    info!("creating confirmation window for peer");
-    let main = handle.get_webview_window("main").unwrap();
-    let win = tauri::WebviewWindowBuilder::new(
-        handle,
-        win_label,
-        WebviewUrl::App("/confirm-link-request".into()),
-    )
-    .title("Confirm Link Request")
-    .inner_size(500.0, 200.0)
-    .resizable(false)
-    // Set the name of the requesting device in local storage of the window so that the
-    // frontend. This is a better method than relying on tauri events which can miss if
-    // they are emitted before the frontend is fully loaded.
-    .initialization_script(&format!(
-        "localStorage.setItem('device-name', '{}')",
-        their_name
-    ))
-    .parent(&main)
-    .unwrap()
-    .build()
-    .unwrap();
-
-    let (etx, erx) = flume::bounded(1);
-    win.listen(LINK_RESPONSE, move |event| match event.payload() {
-        "\"accepted\"" => etx.send(LinkResponse::Accepted).unwrap(),
-        "\"rejected\"" => etx.send(LinkResponse::Rejected).unwrap(),
-        _ => etx.send(LinkResponse::Other).unwrap(),
+/// Refuse further link requests from the device with identity `public_key`
+async fn block_device(ctx: &NetworkContext, name: &str, public_key: &str) {
+    let mut user_config = ctx.config.lock().await;
+    user_config.blocked.push(fdrop_config::BlockedDevice {
+        name: name.to_string(),
+        public_key: Some(public_key.to_string()),
     });
-    let resp = erx.recv_async().await.unwrap();
-    info!("user selected: {:?}", resp);
-    resp
+    if let Err(e) = fdrop_config::save_config(&ctx.data_dir, &user_config) {
+        error!("failed to save blocked device: {}", e);
+    }
 }
 
-async fn handle_postauth_stream(mut stream: TcpStream, rx: Receiver<Bytes>, handle: AppHandle) {
+async fn handle_postauth_stream(
+    stream: SecureStream,
+    outbox: Outbox,
+    pending: PendingTransfers,
+    peer_name: String,
+    ctx: NetworkContext,
+) {
     info!("issued a handler for peer");
+    tokio::spawn(deliver_queued(ctx.clone(), peer_name.clone()));
+    let peer_key = hex::encode(stream.peer_identity.to_bytes());
+    let (mut reader, mut writer) = stream.into_split();
+    // Reading a message is not cancel safe. If it was polled directly inside `select!`, a message
+    // that is partially read when the other branch completes would be lost and the stream would
+    // go out of sync. Hence read in a separate task and forward the messages.
+    let (mtx, mrx) = flume::bounded(100);
+    let reading = tokio::spawn(async move {
+        loop {
+            // Both peers ping each other regularly. A peer that stays silent for longer than that
+            // is gone even if the connection was not closed
+            let message = match tokio::time::timeout(HEARTBEAT_TIMEOUT, reader.read_message()).await
+            {
+                Ok(Ok(message)) => message,
+                Ok(Err(e)) => {
+                    info!("stopped reading from peer: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    warn!("peer stopped responding");
+                    break;
+                }
+            };
+            if mtx.send_async(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    // The user may take a while to answer a transfer offer. The answers arrive as control
+    // requests so that the stream keeps being served in the meantime
+    let control = pending.control_rx();
+    let device_limit =
+        fdrop_config::find_trusted_device(&ctx.data_dir, &peer_key).and_then(|d| d.upload_limit);
+    pending.throttle.set_limit(device_limit);
+    let mut incoming = Incoming::new(peer_key, peer_name, writer.session_hash, pending.control());
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // Set when the peer ended the link
+    let mut unlinked = false;
     loop {
         tokio::select! {
-            Ok(msg) = rx.recv_async() => {
-                if let Err(e) = stream.write_all(&msg).await {
+            msg = outbox.next() => {
+                if let Err(e) = writer.write_message(&msg).await {
                     error!("failed to send message to peer: {}", e);
+                    break;
                 }
                 info!("sent message to peer")
             }
-            Ok((ttype, buff)) = read_stream(&mut stream) => {
+            message = mrx.recv_async() => {
+                let Ok((ttype, buff)) = message else {
+                    break;
+                };
                 info!(?ttype, "got transfer from peer");
-                transfer_handler(ttype, buff, &handle, &mut stream).await;
+                if ttype == TransferType::Unlink {
+                    info!("peer ended the link");
+                    unlinked = true;
+                    break;
+                }
+                transfer_handler(ttype, buff, &ctx, &outbox, &mut incoming, &pending).await;
+            }
+            Ok(request) = control.recv_async() => match request {
+                Control::Decision { transfer_id, accepted } => {
+                    let Some(offer) = incoming.offers.remove(&transfer_id) else {
+                        continue;
+                    };
+                    if accepted {
+                        accept_offer(&ctx, &outbox, &mut incoming, offer).await;
+                    } else {
+                        info!(transfer_id, "user declined the transfer");
+                        decline_transfer(&outbox, transfer_id, "declined by the user").await;
+                        let offer = offer.describe(incoming.peer_name.clone());
+                        let entry = HistoryEntry::offer(&offer, TransferStatus::Declined);
+                        ctx.history.record(entry).await;
+                    }
+                }
+                Control::CancelIncoming { transfer_id, found } => {
+                    let cancelled = cancel_incoming(&ctx, &mut incoming, transfer_id).await;
+                    let _ = found.send(cancelled);
+                    if !cancelled {
+                        warn!(transfer_id, "cannot cancel unknown transfer");
+                        continue;
+                    }
+                    let message = protocol::CancelTransfer {
+                        transfer_id,
+                        incoming: true,
+                    };
+                    let message = protocol::encode(TransferType::CancelTransfer, message);
+                    if let Err(e) = outbox.send(message).await {
+                        error!("failed to send message to peer: {}", e);
+                    }
+                }
+                Control::Unlink { done } => {
+                    let message = protocol::encode(TransferType::Unlink, protocol::Unlink {});
+                    if let Err(e) = writer.write_message(&message).await {
+                        error!("failed to send message to peer: {}", e);
+                    }
+                    let _ = done.send(());
+                    info!("ended the link with peer");
+                    break;
+                }
+            },
+            _ = heartbeat.tick() => {
+                let ping = protocol::encode(TransferType::Ping, protocol::Ping {});
+                if let Err(e) = writer.write_message(&ping).await {
+                    error!("failed to send message to peer: {}", e);
+                    break;
+                }
             }
         }
     }
+    info!("stream with peer closed");
+    // Drops the read half so that the connection is closed
+    reading.abort();
+    let device = incoming.peer_name.clone();
+    let peer_key = incoming.peer_key.clone();
+    disconnect(&ctx, incoming, &outbox, &pending).await;
+    if unlinked {
+        forget_device(&ctx, &device, &peer_key).await;
+    }
+}
+
+/// Clean up after the stream served by `outbox` is closed. Transfers in progress on it are
+/// abandoned and the device is shown as disconnected, unless it has connected again meanwhile
+async fn disconnect(
+    ctx: &NetworkContext,
+    incoming: Incoming,
+    outbox: &Outbox,
+    pending: &PendingTransfers,
+) {
+    outbox.close();
+    pending.abandon();
+    for message_id in pending.take_unacked() {
+        let receipt = MessageReceipt {
+            message_id,
+            device: incoming.peer_name.clone(),
+            status: MessageStatus::Failed,
+        };
+        ctx.emit(MESSAGE_RECEIPT, receipt);
+    }
+    // Partially received files are kept so that the transfers can be resumed later
+    let device = incoming.peer_name;
+    for incoming_file in incoming.files.values() {
+        if incoming_file.directory_id.is_none() {
+            let entry = incoming_file.history_entry(device.clone(), TransferStatus::Failed);
+            ctx.history.record(entry).await;
+        }
+    }
+    for directory in incoming.directories.values() {
+        let entry = directory.history_entry(device.clone(), TransferStatus::Failed);
+        ctx.history.record(entry).await;
+    }
+
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let Some(con) = connection_manager.get_connection_mut(&device) else {
+        return;
+    };
+    if !con.outbox.as_ref().is_some_and(|o| o.same_as(outbox)) {
+        return;
+    }
+    con.outbox = None;
+    con.info.set_state(ConnectionState::Offline);
+    ctx.emit(DEVICE_DISCONNECTED, &con.info);
 }
 
 async fn transfer_handler(
     ttype: TransferType,
     buff: Bytes,
-    handle: &AppHandle,
-    stream: &mut TcpStream,
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    incoming: &mut Incoming,
+    pending: &PendingTransfers,
 ) {
     match ttype {
         TransferType::TextMessage => {
             if let Ok(message) = protocol::protobuf::TextMessage::decode(buff) {
+                let entry = HistoryEntry::new(
+                    incoming.peer_name.clone(),
+                    Direction::Incoming,
+                    TransferKind::Text,
+                    message.contents.clone(),
+                    message.contents.len() as u64,
+                    TransferStatus::Completed,
+                );
+                ctx.history.record(entry).await;
                 let payload = Transfer {
                     ttype,
                     display_content: DisplayContent::Text(message.contents),
                 };
-                handle.emit("transfer", payload).unwrap();
+                ctx.emit(TRANSFER, payload);
+                if message.message_id != 0 {
+                    let ack = protocol::TextMessageAck {
+                        message_id: message.message_id,
+                    };
+                    let resp_message = protocol::encode(TransferType::TextMessageAck, ack);
+                    if let Err(e) = outbox.send(resp_message).await {
+                        error!("failed to send message to peer: {}", e);
+                    }
+                }
             } else {
                 error!("peer sent invalid bytes");
             }
         }
+        TransferType::TextMessageAck => match protocol::TextMessageAck::decode(buff) {
+            Ok(ack) if pending.acknowledge(ack.message_id) => {
+                let receipt = MessageReceipt {
+                    message_id: ack.message_id,
+                    device: incoming.peer_name.clone(),
+                    status: MessageStatus::Delivered,
+                };
+                ctx.emit(MESSAGE_RECEIPT, receipt);
+            }
+            Ok(ack) => warn!(
+                message_id = ack.message_id,
+                "peer acknowledged an unknown message"
+            ),
+            Err(_) => error!("peer sent invalid bytes"),
+        },
+        TransferType::Ping => {
+            let resp_message = protocol::encode(TransferType::Pong, protocol::Pong {});
+            if let Err(e) = outbox.send(resp_message).await {
+                error!("failed to send message to peer: {}", e);
+            }
+        }
+        // Only there to show that the peer is alive
+        TransferType::Pong => {}
+        // Ends the stream, so it is handled by the task serving it
+        TransferType::Unlink => {}
         TransferType::Link => {
-            let user_config_lock = handle.state::<Mutex<UserConfig>>();
-            let user_config = user_config_lock.lock().await;
-            let our_name = user_config.instance_name.clone();
+            let (keypair, listen_port) = {
+                let connection_manager = ctx.connection_manager.lock().await;
+                (
+                    connection_manager.keypair.clone().unwrap(),
+                    connection_manager.listen_port,
+                )
+            };
+            let our_name = ctx.config.lock().await.instance_name.clone();
 
             let resp = protocol::Link {
                 request: None,
                 name: our_name,
                 response: Some(LinkResponse::Accepted.into()),
                 platform: String::from(OUR_PLATFORM),
+                public_key: keypair.public().to_bytes().to_vec(),
+                signature: secure::sign_link(&keypair, &incoming.session_hash),
+                port: listen_port.map(u32::from),
             };
 
             let resp_message = protocol::encode(TransferType::Link, resp);
-            stream.write_all(&resp_message).await.unwrap();
+            if let Err(e) = outbox.send(resp_message).await {
+                error!("failed to send message to peer: {}", e);
+            }
         }
         TransferType::PrepareFileTransfer => {
             if let Ok(message) = protocol::protobuf::PrepareFileTransfer::decode(buff) {
-                let user_config_lock = handle.state::<Mutex<UserConfig>>();
-                let user_config = user_config_lock.lock().await;
-                let mut file_path = user_config.fdrop_dir.clone();
-                // file_path.push(&message.file_name);
-                // if let Ok(_file) = tokio::fs::File::create(&file_path).await {
-                //     info!(?file_path, "created empty file");
-                // }
-                let payload = Transfer {
-                    ttype,
-                    display_content: DisplayContent::DisplayFileTransfer(DisplayFileTransfer {
-                        file_path: message.file_name,
-                        assoc_text: message.assoc_text,
-                    }),
+                // Files inside a directory were accepted along with the directory
+                if message.directory_id.is_some() {
+                    accept_file(ctx, outbox, incoming, message).await;
+                    return;
+                }
+                let pending_offer = Offer::File(message);
+                let offer = pending_offer.describe(incoming.peer_name.clone());
+                offer_transfer(ctx, outbox, incoming, offer, pending_offer).await;
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::FileChunk => {
+            if let Ok(message) = protocol::protobuf::FileChunk::decode(buff) {
+                let Some(incoming_file) = incoming.files.get_mut(&message.transfer_id) else {
+                    warn!(
+                        transfer_id = message.transfer_id,
+                        "peer sent chunk for unknown transfer"
+                    );
+                    return;
                 };
-                handle.emit("transfer", payload).unwrap();
+                if blake3::hash(&message.data).as_bytes()[..] != message.checksum[..] {
+                    // Everything received before this chunk has been verified, so the partial
+                    // file is kept to allow resuming
+                    error!(path = ?incoming_file.path, "chunk failed checksum verification");
+                    let incoming_file = incoming.files.remove(&message.transfer_id).unwrap();
+                    let entry = incoming_file
+                        .history_entry(incoming.peer_name.clone(), TransferStatus::Failed);
+                    ctx.history.record(entry).await;
+                    fail_incoming_transfer(
+                        ctx,
+                        outbox,
+                        message.transfer_id,
+                        CommunicationError::ChecksumMismatch,
+                    )
+                    .await;
+                    return;
+                }
+                let received = incoming_file.received + message.data.len() as u64;
+                if received > incoming_file.size {
+                    // The size was what the transfer policy was checked against, so nothing
+                    // beyond it is accepted
+                    error!(path = ?incoming_file.path, "peer sent more than the announced size");
+                    let incoming_file = incoming.files.remove(&message.transfer_id).unwrap();
+                    let entry = incoming_file
+                        .history_entry(incoming.peer_name.clone(), TransferStatus::Failed);
+                    drop(incoming_file.file);
+                    let _ = tokio::fs::remove_file(&incoming_file.partial_path).await;
+                    let err = CommunicationError::SizeMismatch;
+                    fail_incoming_file(ctx, outbox, message.transfer_id, entry, err).await;
+                    return;
+                }
+                if let Err(e) = incoming_file.file.write_all(&message.data).await {
+                    error!(path = ?incoming_file.path, "failed to write to file: {}", e);
+                    let incoming_file = incoming.files.remove(&message.transfer_id).unwrap();
+                    let entry = incoming_file
+                        .history_entry(incoming.peer_name.clone(), TransferStatus::Failed);
+                    ctx.history.record(entry).await;
+                    return;
+                }
+                incoming_file.received = received;
+                if let Some(progress) = incoming_file.progress.advance(message.data.len() as u64) {
+                    ctx.emit(TRANSFER_PROGRESS, progress);
+                }
             } else {
                 error!("peer sent invalid bytes");
             }
         }
-    }
-}
-
-pub mod commands {
-    use std::path::PathBuf;
-
-    use fdrop_common::human_readable_error;
-    use tokio::task::JoinSet;
-
-    use super::*;
-    #[tauri::command]
-    pub async fn enable_networking(handle: AppHandle) -> Result<(), String> {
-        launch_discovery_service(handle.clone())
-            .await
-            .map_err(|e| NetworkError::from(e))?;
-        accept_connections(handle)
-            .await
-            .map_err(|e| NetworkError::from(e))?;
-        Ok(())
-    }
-
-    #[tauri::command]
-    pub async fn send_text_message(
-        handle: AppHandle,
-        cname: String,
-        contents: String,
-    ) -> Result<(), String> {
-        let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-        let mut connection_manager = cm_lock.lock().await;
-        let con = connection_manager.get_connection_mut(&cname).unwrap();
-
-        let message = protocol::protobuf::TextMessage { contents };
-        let encmsg = protocol::encode(TransferType::TextMessage, message);
+        TransferType::FileTransferEnd => {
+            if let Ok(message) = protocol::protobuf::FileTransferEnd::decode(buff) {
+                let Some(mut incoming_file) = incoming.files.remove(&message.transfer_id) else {
+                    warn!(
+                        transfer_id = message.transfer_id,
+                        "peer ended unknown transfer"
+                    );
+                    return;
+                };
+                let entry = incoming_file
+                    .history_entry(incoming.peer_name.clone(), TransferStatus::Completed);
+                if let Err(e) = incoming_file.file.flush().await {
+                    error!(path = ?incoming_file.path, "failed to write to file: {}", e);
+                    fail_incoming_file(ctx, outbox, message.transfer_id, entry, e.into()).await;
+                    return;
+                }
+                if incoming_file.received != incoming_file.size {
+                    // Keep the partial file around so that the transfer can be resumed
+                    warn!(
+                        path = ?incoming_file.path,
+                        "received {} bytes while peer announced {} bytes",
+                        incoming_file.received,
+                        incoming_file.size
+                    );
+                    let err = CommunicationError::SizeMismatch;
+                    fail_incoming_file(ctx, outbox, message.transfer_id, entry, err).await;
+                    return;
+                }
+                drop(incoming_file.file);
+                match transfer::hash_file(&incoming_file.partial_path).await {
+                    Ok(hash) if hash.as_bytes()[..] == incoming_file.hash[..] => {}
+                    Ok(_) => {
+                        error!(path = ?incoming_file.path, "file failed checksum verification");
+                        let _ = tokio::fs::remove_file(&incoming_file.partial_path).await;
+                        let err = CommunicationError::ChecksumMismatch;
+                        fail_incoming_file(ctx, outbox, message.transfer_id, entry, err).await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!(path = ?incoming_file.path, "failed to verify file: {}", e);
+                        fail_incoming_file(ctx, outbox, message.transfer_id, entry, e.into()).await;
+                        return;
+                    }
+                }
+                if let Err(e) =
+                    transfer::move_file(&incoming_file.partial_path, &incoming_file.path).await
+                {
+                    error!(path = ?incoming_file.path, "failed to move received file: {}", e);
+                    fail_incoming_file(ctx, outbox, message.transfer_id, entry, e.into()).await;
+                    return;
+                }
+                info!(path = ?incoming_file.path, "received file from peer");
+                let file_path = incoming_file.path.to_string_lossy().to_string();
+                let resp = protocol::FileTransferComplete {
+                    transfer_id: message.transfer_id,
+                    file_name: incoming_file
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string(),
+                };
+                let resp_message = protocol::encode(TransferType::FileTransferComplete, resp);
+                if let Err(e) = outbox.send(resp_message).await {
+                    error!("failed to send message to peer: {}", e);
+                }
+                let payload = TransferComplete {
+                    transfer_id: message.transfer_id,
+                    file_path,
+                };
+                if incoming_file.directory_id.is_none() {
+                    ctx.history.record(entry).await;
+                }
+                ctx.emit(TRANSFER_COMPLETE, payload);
 
-        let tx = con.tx.as_mut().unwrap();
-        tx.send_async(encmsg).await.unwrap();
-        Ok(())
+                let Some(directory_id) = incoming_file.directory_id else {
+                    return;
+                };
+                if let Some(directory) = incoming.directories.get_mut(&directory_id) {
+                    directory.progress.files_done += 1;
+                    directory.progress.bytes_done += incoming_file.size;
+                    let done = directory.progress.files_done >= directory.progress.file_count;
+                    if done {
+                        info!(root = ?directory.root, "received directory from peer");
+                        let entry = directory
+                            .history_entry(incoming.peer_name.clone(), TransferStatus::Completed);
+                        ctx.history.record(entry).await;
+                    }
+                    ctx.emit(DIRECTORY_PROGRESS, &directory.progress);
+                    if done {
+                        incoming.directories.remove(&directory_id);
+                    }
+                }
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::PrepareDirectoryTransfer => {
+            if let Ok(message) = protocol::protobuf::PrepareDirectoryTransfer::decode(buff) {
+                let pending_offer = Offer::Directory(message);
+                let offer = pending_offer.describe(incoming.peer_name.clone());
+                offer_transfer(ctx, outbox, incoming, offer, pending_offer).await;
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::DirectoryManifest => {
+            if let Ok(message) = protocol::protobuf::DirectoryManifest::decode(buff) {
+                let Some(directory) = incoming.directories.get(&message.directory_id) else {
+                    warn!(
+                        directory_id = message.directory_id,
+                        "peer sent manifest for unknown directory"
+                    );
+                    return;
+                };
+                // Files create their parent directories when they are received. Only the
+                // directories need to be created here so that empty ones are not lost
+                for entry in message.entries.iter().filter(|e| e.directory) {
+                    let Some(relative) = transfer::relative_path(&entry.path) else {
+                        error!("peer sent an invalid directory path");
+                        continue;
+                    };
+                    let path = directory.root.join(relative);
+                    if let Err(e) = tokio::fs::create_dir_all(&path).await {
+                        error!(?path, "failed to create directory: {}", e);
+                    }
+                }
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::FileTransferResponse => {
+            if let Ok(message) = protocol::protobuf::FileTransferResponse::decode(buff) {
+                let transfer_id = message.transfer_id;
+                if !pending.resolve(message) {
+                    warn!(transfer_id, "peer answered unknown transfer");
+                }
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::CancelTransfer => {
+            if let Ok(message) = protocol::protobuf::CancelTransfer::decode(buff) {
+                let transfer_id = message.transfer_id;
+                if !message.incoming {
+                    info!(transfer_id, "peer cancelled the transfer");
+                    cancel_incoming(ctx, incoming, transfer_id).await;
+                } else if pending.cancel(transfer_id) {
+                    info!(transfer_id, "peer stopped receiving the transfer");
+                    let payload = TransferCancelled {
+                        transfer_id,
+                        device: incoming.peer_name.clone(),
+                        direction: Direction::Outgoing,
+                    };
+                    ctx.emit(TRANSFER_CANCELLED, payload);
+                }
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::FileTransferFailed => {
+            if let Ok(message) = protocol::protobuf::FileTransferFailed::decode(buff) {
+                error!(
+                    transfer_id = message.transfer_id,
+                    "peer failed to receive the file: {}", message.reason
+                );
+                // Stops sending the rest of the file, the peer threw it away
+                pending.fail(message.transfer_id, message.reason.clone());
+                let payload = TransferFailed {
+                    transfer_id: message.transfer_id,
+                    error: message.reason,
+                };
+                ctx.emit(TRANSFER_FAILED, payload);
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
+        TransferType::FileTransferComplete => {
+            if let Ok(message) = protocol::protobuf::FileTransferComplete::decode(buff) {
+                info!(transfer_id = message.transfer_id, "peer received the file");
+                pending.confirm(message.transfer_id);
+                let payload = TransferComplete {
+                    transfer_id: message.transfer_id,
+                    file_path: message.file_name,
+                };
+                ctx.emit(TRANSFER_COMPLETE, payload);
+            } else {
+                error!("peer sent invalid bytes");
+            }
+        }
     }
+}
 
-    #[tauri::command]
-    pub async fn send_files(
-        handle: AppHandle,
-        cname: String,
-        file_paths: Vec<String>,
-        assoc_text: Option<String>,
-    ) -> Result<(), String> {
-        let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-        let mut connection_manager = cm_lock.lock().await;
-        let con = connection_manager.get_connection_mut(&cname).unwrap();
-
-        let mut join_set = JoinSet::new();
-
-        for file_path in file_paths {
-            let mut tx = con.tx.clone();
-            let assoc_text = assoc_text.clone();
-            join_set.spawn(async move {
-                let file_path = PathBuf::from(file_path);
-                let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
-                let file = tokio::fs::File::open(file_path)
-                    .await
-                    .map_err(|e| human_readable_error(&e))?;
-                // TODO: handle error
-                let size = file
-                    .metadata()
-                    .await
-                    .map_err(|e| human_readable_error(&e))?
-                    .len();
-
-                let transfer = protocol::protobuf::PrepareFileTransfer {
-                    file_name,
-                    size,
-                    assoc_text,
+/// Answer a transfer offered by the peer as the policy of the peer says, asking the user when
+/// the policy leaves it open. The answer of the user arrives later through `incoming.control`
+async fn offer_transfer(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    incoming: &mut Incoming,
+    offer: TransferOffer,
+    pending_offer: Offer,
+) {
+    let policy = fdrop_config::transfer_policy(&ctx.data_dir, &incoming.peer_key);
+    match transfer::policy_decision(&policy, offer.size) {
+        PolicyDecision::Accept => accept_offer(ctx, outbox, incoming, pending_offer).await,
+        PolicyDecision::Decline(reason) => {
+            info!(
+                transfer_id = offer.transfer_id,
+                "declining transfer: {}", reason
+            );
+            decline_transfer(outbox, offer.transfer_id, &reason).await;
+            let entry = HistoryEntry::offer(&offer, TransferStatus::Declined);
+            ctx.history.record(entry).await;
+        }
+        PolicyDecision::Ask => {
+            incoming.offers.insert(offer.transfer_id, pending_offer);
+            let frontend = ctx.frontend.clone();
+            let control = incoming.control.clone();
+            tokio::spawn(async move {
+                let accepted = frontend.confirm_transfer(&offer).await;
+                let decision = Control::Decision {
+                    transfer_id: offer.transfer_id,
+                    accepted,
                 };
-                let enctransfer = protocol::encode(TransferType::PrepareFileTransfer, transfer);
-                tx.as_mut().unwrap().send_async(enctransfer).await.unwrap();
-                Ok::<(), String>(())
+                let _ = control.send_async(decision).await;
             });
         }
+    }
+}
 
-        join_set.join_all().await;
-
-        Ok(())
+async fn accept_offer(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    incoming: &mut Incoming,
+    offer: Offer,
+) {
+    match offer {
+        Offer::File(message) => accept_file(ctx, outbox, incoming, message).await,
+        Offer::Directory(message) => accept_directory(ctx, outbox, incoming, message).await,
     }
+}
 
-    #[tauri::command]
-    pub async fn link_device_by_name(
-        handle: AppHandle,
-        cname: String,
-    ) -> Result<&'static str, String> {
-        let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-        let user_config_lock = handle.state::<Mutex<UserConfig>>();
-        let mut connection_manager = cm_lock.lock().await;
-        let user_config = user_config_lock.lock().await;
-        let con = connection_manager.get_connection_mut(&cname).unwrap();
-        let our_name = &user_config.instance_name.clone();
-
-        let res = con
-            .send_link_request(handle.clone(), our_name)
-            .await
-            .map_err(|e| NetworkError::from(e))?;
-        let res = match res {
-            LinkResponse::Accepted => {
-                handle.emit(DEVICE_LINKED, con.info.clone()).unwrap();
-                Ok("accepted")
-            }
-            LinkResponse::Rejected => {
-                let cm_lock = handle.state::<Mutex<ConnectionManager>>();
-                let mut connection_manager = cm_lock.lock().await;
-                let win_label = "rejected-link-request-".to_string()
-                    + &connection_manager.active_link_requests.to_string();
-                connection_manager.active_link_requests += 1;
-
-                let main = handle.get_webview_window("main").unwrap();
-                tauri::WebviewWindowBuilder::new(
-                    &handle,
-                    win_label,
-                    WebviewUrl::App("/rejected-link-request".into()),
-                )
-                .title("Link Request Rejected")
-                .inner_size(500.0, 150.0)
-                .resizable(false)
-                // Set the name of the requesting device in local storage of the window so that the
-                // frontend. This is a better method than relying on tauri events which can miss if
-                // they are emitted before the frontend is fully loaded.
-                .initialization_script(&format!(
-                    "localStorage.setItem('device-name', '{}')",
-                    con.info.name
-                ))
-                .parent(&main)
-                .unwrap()
-                .build()
-                .unwrap();
-                Ok("rejected")
-            }
-            LinkResponse::Other => Ok("other"),
+/// Stop receiving the transfer `transfer_id` and delete the data received for it. For a directory
+/// this includes the files in it that are not complete yet. Returns `false` if no such transfer
+/// was being received or offered
+async fn cancel_incoming(ctx: &NetworkContext, incoming: &mut Incoming, transfer_id: u32) -> bool {
+    let device = incoming.peer_name.clone();
+    let mut entry = None;
+    if let Some(offer) = incoming.offers.remove(&transfer_id) {
+        let offer = offer.describe(device.clone());
+        entry = Some(HistoryEntry::offer(&offer, TransferStatus::Cancelled));
+    }
+    if let Some(directory) = incoming.directories.remove(&transfer_id) {
+        entry = Some(directory.history_entry(device.clone(), TransferStatus::Cancelled));
+    }
+    let mut found = entry.is_some();
+    let cancelled: Vec<u32> = incoming
+        .files
+        .iter()
+        .filter(|(id, f)| **id == transfer_id || f.directory_id == Some(transfer_id))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in cancelled {
+        let incoming_file = incoming.files.remove(&id).unwrap();
+        if id == transfer_id {
+            entry = Some(incoming_file.history_entry(device.clone(), TransferStatus::Cancelled));
+        }
+        drop(incoming_file.file);
+        if let Err(e) = tokio::fs::remove_file(&incoming_file.partial_path).await {
+            error!(path = ?incoming_file.partial_path, "failed to remove partial file: {}", e);
+        }
+        found = true;
+    }
+    if let Some(entry) = entry {
+        ctx.history.record(entry).await;
+    }
+    if found {
+        let payload = TransferCancelled {
+            transfer_id,
+            device,
+            direction: Direction::Incoming,
         };
-        res
+        ctx.emit(TRANSFER_CANCELLED, payload);
+    }
+    found
+}
+
+/// Tell the peer that the transfer `transfer_id` will not be received
+async fn decline_transfer(outbox: &Outbox, transfer_id: u32, reason: &str) {
+    let resp = protocol::FileTransferResponse {
+        transfer_id,
+        accepted: false,
+        offset: 0,
+        reason: reason.to_string(),
+    };
+    let resp_message = protocol::encode(TransferType::FileTransferResponse, resp);
+    if let Err(e) = outbox.send(resp_message).await {
+        error!("failed to send message to peer: {}", e);
+    }
+}
+
+/// Start receiving the file described by `message` and tell the peer where to start sending from
+async fn accept_file(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    incoming: &mut Incoming,
+    message: protocol::PrepareFileTransfer,
+) {
+    let file_path = if let Some(directory_id) = message.directory_id {
+        let Some(directory) = incoming.directories.get_mut(&directory_id) else {
+            warn!(directory_id, "peer sent file for unknown directory");
+            decline_transfer(outbox, message.transfer_id, "unknown directory").await;
+            return;
+        };
+        let accepted_size = directory.accepted_size.saturating_add(message.size);
+        if accepted_size > directory.progress.total_size {
+            error!(
+                directory_id,
+                "peer sent more than the announced size of the directory"
+            );
+            let reason = CommunicationError::SizeMismatch.to_string();
+            decline_transfer(outbox, message.transfer_id, &reason).await;
+            return;
+        }
+        let Some(relative) = transfer::relative_path(&message.file_name) else {
+            error!("peer sent an invalid file path");
+            decline_transfer(outbox, message.transfer_id, "invalid file path").await;
+            return;
+        };
+        directory.accepted_size = accepted_size;
+        let file_path = directory.root.join(relative);
+        if let Err(e) = tokio::fs::create_dir_all(file_path.parent().unwrap()).await {
+            error!(?file_path, "failed to create directory: {}", e);
+            decline_transfer(outbox, message.transfer_id, &e.to_string()).await;
+            return;
+        }
+        file_path
+    } else {
+        // Only keep the last component so that the peer cannot write outside the
+        // FDrop folder
+        let Some(file_name) = Path::new(&message.file_name).file_name() else {
+            error!("peer sent an invalid file name");
+            decline_transfer(outbox, message.transfer_id, "invalid file name").await;
+            return;
+        };
+        let user_config = ctx.config.lock().await;
+        user_config.fdrop_dir.join(file_name)
+    };
+    let partial_path = transfer::partial_path(&ctx.data_dir, &file_path, message.size);
+    let file = match open_partial_file(&partial_path, message.size).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!(?partial_path, "failed to create file: {}", e);
+            decline_transfer(outbox, message.transfer_id, &e.to_string()).await;
+            return;
+        }
+    };
+    let offset = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    if offset > 0 {
+        info!(?file_path, offset, "resuming partially received file");
+    } else {
+        info!(?file_path, "created empty file");
+    }
+    let progress = ProgressTracker::new(
+        &ctx.transfers,
+        message.transfer_id,
+        incoming.peer_name.clone(),
+        Direction::Incoming,
+        message.file_name.clone(),
+        offset,
+        message.size,
+    );
+    incoming.files.insert(
+        message.transfer_id,
+        IncomingFile {
+            file,
+            path: file_path,
+            partial_path,
+            size: message.size,
+            received: offset,
+            hash: message.hash,
+            directory_id: message.directory_id,
+            assoc_text: message.assoc_text.clone(),
+            progress,
+        },
+    );
+    let resp = protocol::FileTransferResponse {
+        transfer_id: message.transfer_id,
+        accepted: true,
+        offset,
+        reason: String::new(),
+    };
+    let resp_message = protocol::encode(TransferType::FileTransferResponse, resp);
+    if let Err(e) = outbox.send(resp_message).await {
+        error!("failed to send message to peer: {}", e);
+    }
+    // Files inside a directory are shown as part of the directory transfer
+    if message.directory_id.is_some() {
+        return;
+    }
+    let payload = Transfer {
+        ttype: TransferType::PrepareFileTransfer,
+        display_content: DisplayContent::DisplayFileTransfer(DisplayFileTransfer {
+            file_path: message.file_name,
+            assoc_text: message.assoc_text,
+        }),
+    };
+    ctx.emit(TRANSFER, payload);
+}
+
+/// Start receiving the directory described by `message`. The files in it are accepted as they
+/// arrive
+async fn accept_directory(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    incoming: &mut Incoming,
+    message: protocol::PrepareDirectoryTransfer,
+) {
+    let Some(name) = Path::new(&message.name).file_name() else {
+        error!("peer sent an invalid directory name");
+        decline_transfer(outbox, message.directory_id, "invalid directory name").await;
+        return;
+    };
+    let root = ctx.config.lock().await.fdrop_dir.join(name);
+    if let Err(e) = tokio::fs::create_dir_all(&root).await {
+        error!(?root, "failed to create directory: {}", e);
+        decline_transfer(outbox, message.directory_id, &e.to_string()).await;
+        return;
+    }
+    info!(?root, "receiving directory from peer");
+    let progress = DirectoryProgress {
+        directory_id: message.directory_id,
+        name: message.name.clone(),
+        files_done: 0,
+        file_count: message.file_count,
+        bytes_done: 0,
+        total_size: message.total_size,
+    };
+    ctx.emit(DIRECTORY_PROGRESS, &progress);
+    incoming.directories.insert(
+        message.directory_id,
+        IncomingDirectory {
+            root,
+            assoc_text: message.assoc_text.clone(),
+            progress,
+            accepted_size: 0,
+        },
+    );
+    let resp = protocol::FileTransferResponse {
+        transfer_id: message.directory_id,
+        accepted: true,
+        offset: 0,
+        reason: String::new(),
+    };
+    let resp_message = protocol::encode(TransferType::FileTransferResponse, resp);
+    if let Err(e) = outbox.send(resp_message).await {
+        error!("failed to send message to peer: {}", e);
+    }
+    let payload = Transfer {
+        ttype: TransferType::PrepareDirectoryTransfer,
+        display_content: DisplayContent::DisplayFileTransfer(DisplayFileTransfer {
+            file_path: message.name,
+            assoc_text: message.assoc_text,
+        }),
+    };
+    ctx.emit(TRANSFER, payload);
+}
+
+/// End the link with the device `cname`. If the device is connected, it is told to forget this
+/// device as well. Either way it is no longer trusted and nothing queued for it is delivered
+pub async fn unlink_device(ctx: &NetworkContext, cname: &str) -> Result<(), NetworkError> {
+    let (device, public_key, done) = {
+        let mut connection_manager = ctx.connection_manager.lock().await;
+        match connection_manager.get_connection_mut(cname) {
+            Some(con) => {
+                let mut done = None;
+                // The task serving the stream tells the peer and closes the stream. Taking the
+                // outbox keeps anything else from being sent in the meantime
+                if con.outbox.take().is_some() {
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    let _ = con.pending.control().send(Control::Unlink { done: tx });
+                    con.info.set_state(ConnectionState::Offline);
+                    done = Some(rx);
+                }
+                let public_key = con.public_key.as_ref().map(|pk| hex::encode(pk.to_bytes()));
+                (con.info.name.clone(), public_key, done)
+            }
+            None => (full_name(cname), None, None),
+        }
+    };
+    if let Some(done) = done {
+        let _ = done.await;
+    }
+
+    let public_key = match public_key {
+        Some(public_key) => public_key,
+        None => fdrop_config::read_trusted_devices(&ctx.data_dir)?
+            .into_iter()
+            .find(|d| full_name(&d.name) == device)
+            .map(|d| d.public_key)
+            .ok_or(CommunicationError::NotLinked)?,
+    };
+    if !fdrop_config::is_trusted_device(&ctx.data_dir, &public_key) {
+        return Err(CommunicationError::NotLinked.into());
+    }
+    info!(device, "ending the link with device");
+    forget_device(ctx, &device, &public_key).await;
+    Ok(())
+}
+
+/// Stop trusting the device with the identity `public_key`. A device that is still connected is
+/// unlinked, so that the stream to it does not outlive the trust. Returns `false` if no such
+/// device was trusted
+pub async fn remove_trusted_device(
+    ctx: &NetworkContext,
+    public_key: &str,
+) -> Result<bool, NetworkError> {
+    let connected = {
+        let connection_manager = ctx.connection_manager.lock().await;
+        connection_manager
+            .available_connections
+            .values()
+            .find(|c| {
+                c.outbox.is_some()
+                    && c.public_key
+                        .as_ref()
+                        .is_some_and(|pk| hex::encode(pk.to_bytes()) == public_key)
+            })
+            .map(|c| c.info.name.clone())
+    };
+    match connected {
+        Some(device) => match unlink_device(ctx, &device).await {
+            Ok(()) => Ok(true),
+            Err(NetworkError::CommunicationError(CommunicationError::NotLinked)) => Ok(false),
+            Err(e) => Err(e),
+        },
+        None => Ok(fdrop_config::remove_trusted_device(
+            &ctx.data_dir,
+            public_key,
+        )?),
+    }
+}
+
+/// Forget the device `device` with the identity `public_key` once the link with it has ended
+async fn forget_device(ctx: &NetworkContext, device: &str, public_key: &str) {
+    if let Err(e) = fdrop_config::remove_trusted_device(&ctx.data_dir, public_key) {
+        error!("failed to remove linked device: {}", e);
+    }
+    if let Err(e) = ctx.queue.clear(device).await {
+        error!("failed to drop the items queued for the device: {}", e);
+    }
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let info = match connection_manager.get_connection_mut(device) {
+        Some(con) => con.info.clone(),
+        None => ConnectionInfo {
+            name: device.to_string(),
+            linked: false,
+            state: ConnectionState::Offline,
+            platform: None,
+        },
+    };
+    ctx.emit(DEVICE_UNLINKED, &info);
+}
+
+/// Cancel the transfer `transfer_id` to or from the linked device `cname`. The device is told to
+/// stop as well and a received file is deleted along with the data received for it
+pub async fn cancel_transfer(
+    ctx: &NetworkContext,
+    cname: &str,
+    transfer_id: u32,
+    direction: Direction,
+) -> Result<(), NetworkError> {
+    let channel = linked_channel(ctx, cname).await?;
+    match direction {
+        Direction::Outgoing => {
+            if !channel.pending.cancel(transfer_id) {
+                return Err(CommunicationError::TransferNotFound.into());
+            }
+            channel.outbox.discard(transfer_id);
+            let message = protocol::CancelTransfer {
+                transfer_id,
+                incoming: false,
+            };
+            let message = protocol::encode(TransferType::CancelTransfer, message);
+            channel.outbox.send(message).await?;
+            let payload = TransferCancelled {
+                transfer_id,
+                device: channel.device,
+                direction,
+            };
+            ctx.emit(TRANSFER_CANCELLED, payload);
+        }
+        // The state of incoming transfers is owned by the task serving the stream
+        Direction::Incoming => {
+            let (found, rx) = tokio::sync::oneshot::channel();
+            let request = Control::CancelIncoming { transfer_id, found };
+            channel
+                .pending
+                .control()
+                .send_async(request)
+                .await
+                .map_err(|_| CommunicationError::Disconnected)?;
+            if !rx.await.map_err(|_| CommunicationError::Disconnected)? {
+                return Err(CommunicationError::TransferNotFound.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Limit the rate at which data is sent to all devices together, in bytes per second. `None`
+/// removes the limit. The limit is saved in the configuration and applies to the connections
+/// right away
+pub async fn set_upload_limit(
+    ctx: &NetworkContext,
+    limit: Option<u64>,
+) -> Result<(), NetworkError> {
+    let mut user_config = ctx.config.lock().await;
+    user_config.upload_limit = limit;
+    fdrop_config::save_config(&ctx.data_dir, &user_config)?;
+    ctx.upload_limit.set_limit(limit);
+    Ok(())
+}
+
+/// Limit the rate at which data is sent to the linked device `cname`, in bytes per second. `None`
+/// removes the limit. The limit is saved with the trusted device and applies to the connection
+/// right away
+pub async fn set_device_upload_limit(
+    ctx: &NetworkContext,
+    cname: &str,
+    limit: Option<u64>,
+) -> Result<(), NetworkError> {
+    let (throttle, public_key) = {
+        let connection_manager = ctx.connection_manager.lock().await;
+        let con = connection_manager
+            .get_connection(cname)
+            .ok_or(CommunicationError::PeerNotFound)?;
+        (con.pending.throttle.clone(), con.public_key.clone())
+    };
+    let public_key = public_key.ok_or(CommunicationError::NotLinked)?;
+    let public_key = hex::encode(public_key.to_bytes());
+    if !fdrop_config::set_upload_limit(&ctx.data_dir, &public_key, limit)? {
+        return Err(CommunicationError::NotLinked.into());
+    }
+    throttle.set_limit(limit);
+    Ok(())
+}
+
+/// Get the latest progress of every file being sent or received
+pub fn active_transfers(ctx: &NetworkContext) -> Vec<TransferProgress> {
+    ctx.transfers.list()
+}
+
+/// Send a link request to the device `cname` and notify the frontend if it gets accepted
+pub async fn link_device(ctx: &NetworkContext, cname: &str) -> Result<LinkResponse, NetworkError> {
+    let our_name = ctx.config.lock().await.instance_name.clone();
+    // The device may take long to answer, so the connection manager is only locked to look up the
+    // device and to record the outcome
+    let (target, keypair, listen_port) = {
+        let mut connection_manager = ctx.connection_manager.lock().await;
+        let keypair = connection_manager.keypair.clone().unwrap();
+        let listen_port = connection_manager.listen_port;
+        let con = connection_manager
+            .get_connection_mut(cname)
+            .ok_or(CommunicationError::PeerNotFound)?;
+        if con.outbox.is_some() {
+            return Ok(LinkResponse::Accepted);
+        }
+        con.info.set_state(ConnectionState::Connecting);
+        ctx.emit(DEVICE_CONNECTING, &con.info);
+        (con.link_target(), keypair, listen_port)
+    };
+
+    let res = send_link_request(ctx, &target, &our_name, &keypair, listen_port).await;
+
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let con = connection_manager
+        .get_connection_mut(cname)
+        .ok_or(CommunicationError::PeerNotFound)?;
+    // The device may have linked with us in the meantime
+    if con.outbox.is_some() {
+        return Ok(LinkResponse::Accepted);
+    }
+    if !matches!(res, Ok(Ok(_))) {
+        con.info.set_state(ConnectionState::Offline);
+        ctx.emit(DEVICE_DISCONNECTED, &con.info);
+    }
+    let linked = match res? {
+        Ok(linked) => linked,
+        Err(res) => return Ok(res),
+    };
+    // Devices added by address only get their real name once linked
+    let name = if con.added_by_address {
+        full_name(&linked.name)
+    } else {
+        cname.to_string()
+    };
+    // Another device may be known under that name already
+    let taken = name != cname
+        && connection_manager.get_connection(&name).is_some_and(|c| {
+            c.public_key
+                .as_ref()
+                .is_some_and(|pk| *pk != linked.peer_key)
+        });
+    let con = connection_manager.get_connection_mut(cname).unwrap();
+    if taken {
+        error!(name, "peer claims the name of another device");
+        con.info.set_state(ConnectionState::Offline);
+        ctx.emit(DEVICE_DISCONNECTED, &con.info);
+        return Err(CommunicationError::IdentityMismatch.into());
+    }
+    let outbox = Outbox::default();
+    con.outbox = Some(outbox.clone());
+    con.info.set_state(ConnectionState::Linked);
+    con.info.name = name;
+    con.added_by_address = false;
+    con.info.platform = Some(linked.platform);
+    if let Err(e) = fdrop_config::trust_device(
+        &ctx.data_dir,
+        &hex::encode(linked.peer_key.to_bytes()),
+        &linked.name,
+        con.info.platform.clone(),
+    ) {
+        error!("failed to save linked device: {}", e);
+    }
+    con.public_key = Some(linked.peer_key);
+    tokio::spawn(handle_postauth_stream(
+        linked.stream,
+        outbox,
+        con.pending.clone(),
+        con.info.name.clone(),
+        ctx.clone(),
+    ));
+    info!("successfully linked with peer");
+
+    let info = con.info.clone();
+    // A device added by address is known under a placeholder name until it is linked
+    if info.name != cname {
+        let con = connection_manager
+            .available_connections
+            .remove(cname)
+            .unwrap();
+        let removed = ConnectionInfo {
+            name: cname.to_string(),
+            ..info.clone()
+        };
+        ctx.emit(DEVICE_REMOVED, &removed);
+        ctx.emit(DEVICE_DISCOVERED, &info);
+        connection_manager
+            .available_connections
+            .insert(info.name.clone(), con);
+    }
+    ctx.emit(DEVICE_LINKED, &info);
+    Ok(LinkResponse::Accepted)
+}
+
+/// Send the file at `file_path` to the peer as `file_name`. Returns the size of the file
+async fn send_file(
+    ctx: &NetworkContext,
+    channel: &LinkedChannel,
+    file_path: &Path,
+    file_name: String,
+    assoc_text: Option<String>,
+    directory_id: Option<u32>,
+) -> Result<u64, CommunicationError> {
+    let mut file = tokio::fs::File::open(file_path).await?;
+    let size = file.metadata().await?.len();
+    let hash = transfer::hash_file(file_path).await?;
+    let transfer_id = transfer::next_transfer_id();
+
+    let transfer = protocol::protobuf::PrepareFileTransfer {
+        file_name: file_name.clone(),
+        size,
+        assoc_text,
+        transfer_id,
+        hash: hash.as_bytes().to_vec(),
+        directory_id,
+    };
+    let enctransfer = protocol::encode(TransferType::PrepareFileTransfer, transfer);
+    let mut outgoing = channel.pending.register(transfer_id);
+    channel.outbox.send(enctransfer).await?;
+
+    // Wait for the peer to accept the file and tell how much of it it already has
+    let offset = wait_for_acceptance(&mut outgoing).await?;
+    if offset > 0 {
+        info!(?file_path, offset, "resuming file transfer");
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+
+    let mut progress = ProgressTracker::new(
+        &ctx.transfers,
+        transfer_id,
+        channel.device.clone(),
+        Direction::Outgoing,
+        file_name,
+        offset,
+        size,
+    );
+    let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let directory_cancelled = directory_id.is_some_and(|id| channel.pending.is_cancelled(id));
+        if directory_cancelled {
+            info!(?file_path, "stopped sending file of cancelled directory");
+            return Err(CommunicationError::TransferCancelled);
+        }
+        if let Err(err) = outgoing_stopped(outgoing.state()) {
+            info!(?file_path, "stopped sending file: {err}");
+            return Err(err);
+        }
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let chunk = protocol::protobuf::FileChunk {
+            transfer_id,
+            data: buf[..n].to_vec(),
+            checksum: blake3::hash(&buf[..n]).as_bytes().to_vec(),
+        };
+        let encchunk = protocol::encode_stream(TransferType::FileChunk, transfer_id, chunk);
+        // Throttled here rather than when writing so that other frames are not held back
+        channel.pending.throttle.wait(encchunk.len()).await;
+        ctx.upload_limit.wait(encchunk.len()).await;
+        channel.outbox.send(encchunk).await?;
+        if let Some(progress) = progress.advance(n as u64) {
+            ctx.emit(TRANSFER_PROGRESS, progress);
+        }
+    }
+
+    let end = protocol::protobuf::FileTransferEnd { transfer_id };
+    let encend = protocol::encode_stream(TransferType::FileTransferEnd, transfer_id, end);
+    channel.outbox.send(encend).await?;
+    // The file only counts as sent once the peer verified and stored it
+    if !outgoing.confirmation().await {
+        outgoing_stopped(outgoing.state())?;
+        return Err(CommunicationError::Disconnected);
+    }
+    info!(?file_path, "sent file to peer");
+    Ok(size)
+}
+
+/// Fail with the reason why an outgoing transfer stopped, if it did
+fn outgoing_stopped(state: OutgoingState) -> Result<(), CommunicationError> {
+    match state {
+        OutgoingState::Sending => Ok(()),
+        OutgoingState::Cancelled => Err(CommunicationError::TransferCancelled),
+        OutgoingState::Failed(reason) => Err(CommunicationError::TransferFailed(reason)),
+    }
+}
+
+/// Wait for the peer to answer a transfer. Returns the offset to start sending from if the
+/// transfer was accepted
+async fn wait_for_acceptance(outgoing: &mut OutgoingTransfer) -> Result<u64, CommunicationError> {
+    let Some(response) = outgoing.response().await else {
+        outgoing_stopped(outgoing.state())?;
+        return Err(CommunicationError::NoResponse);
+    };
+    if !response.accepted {
+        return Err(CommunicationError::TransferDeclined(response.reason));
+    }
+    Ok(response.offset)
+}
+
+/// Notify both the frontend and the peer that receiving a file failed
+async fn fail_incoming_transfer(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    transfer_id: u32,
+    err: CommunicationError,
+) {
+    let error = human_readable_error(&err);
+    let resp = protocol::FileTransferFailed {
+        transfer_id,
+        reason: err.to_string(),
+    };
+    let resp_message = protocol::encode(TransferType::FileTransferFailed, resp);
+    if let Err(e) = outbox.send(resp_message).await {
+        error!("failed to send message to peer: {}", e);
+    }
+    ctx.emit(TRANSFER_FAILED, TransferFailed { transfer_id, error });
+}
+
+/// Record the file in `entry` as failed and notify both the frontend and the peer
+async fn fail_incoming_file(
+    ctx: &NetworkContext,
+    outbox: &Outbox,
+    transfer_id: u32,
+    mut entry: HistoryEntry,
+    err: CommunicationError,
+) {
+    entry.status = TransferStatus::Failed;
+    ctx.history.record(entry).await;
+    fail_incoming_transfer(ctx, outbox, transfer_id, err).await;
+}
+
+/// Open the file where the contents of an incoming file are written, keeping any data already
+/// received for it. A partial file larger than the file itself is not usable and hence discarded
+async fn open_partial_file(partial_path: &Path, size: u64) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::create_dir_all(partial_path.parent().unwrap()).await?;
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partial_path)
+        .await?;
+    if file.metadata().await?.len() > size {
+        file.set_len(0).await?;
+    }
+    Ok(file)
+}
+
+/// Channel to a linked device along with the transfers waiting on it
+#[derive(Clone)]
+struct LinkedChannel {
+    outbox: Outbox,
+    pending: PendingTransfers,
+    /// Name of the device
+    device: String,
+}
+
+/// Get the channel to the linked device `cname`
+async fn linked_channel(
+    ctx: &NetworkContext,
+    cname: &str,
+) -> Result<LinkedChannel, CommunicationError> {
+    let mut connection_manager = ctx.connection_manager.lock().await;
+    let con = connection_manager
+        .get_connection_mut(cname)
+        .ok_or(CommunicationError::PeerNotFound)?;
+    let outbox = con.outbox.clone().ok_or(CommunicationError::NotLinked)?;
+    Ok(LinkedChannel {
+        outbox,
+        pending: con.pending.clone(),
+        device: con.info.name.clone(),
+    })
+}
+
+/// Where the items sent to a device go
+enum Route {
+    Linked(LinkedChannel),
+    /// The device is offline or items for it are waiting already. Holds the name of the device
+    Queued(String),
+}
+
+/// Find out where items sent to the device `cname` go. Only devices that were linked before get
+/// items queued for them while they are offline
+async fn route(ctx: &NetworkContext, cname: &str) -> Result<Route, CommunicationError> {
+    let err = match linked_channel(ctx, cname).await {
+        Ok(channel) if ctx.queue.is_waiting(&channel.device).await => {
+            return Ok(Route::Queued(channel.device));
+        }
+        Ok(channel) => return Ok(Route::Linked(channel)),
+        Err(e) => e,
+    };
+    let device = full_name(cname);
+    let known = fdrop_config::read_trusted_devices(&ctx.data_dir)
+        .is_ok_and(|devices| devices.iter().any(|d| full_name(&d.name) == device));
+    if known {
+        Ok(Route::Queued(device))
+    } else {
+        Err(err)
+    }
+}
+
+/// Queue `content` for the device `device`. It is delivered right away if the device is linked
+async fn enqueue(
+    ctx: &NetworkContext,
+    device: String,
+    content: QueuedContent,
+) -> Result<(), NetworkError> {
+    let item = ctx
+        .queue
+        .push(device, content)
+        .await
+        .map_err(NetworkError::QueueError)?;
+    info!(device = item.device, "queued item for offline device");
+    ctx.emit(DELIVERY, Delivery::new(&item, DeliveryState::Queued));
+    tokio::spawn(deliver_queued(ctx.clone(), item.device));
+    Ok(())
+}
+
+/// Deliver the items queued for the device `device` one after the other, for as long as it stays
+/// linked
+async fn deliver_queued(ctx: NetworkContext, device: String) {
+    if !ctx.queue.start_delivery(&device).await {
+        return;
+    }
+    loop {
+        let Ok(channel) = linked_channel(&ctx, &device).await else {
+            ctx.queue.stop_delivery(&device).await;
+            return;
+        };
+        let item = match ctx.queue.next(&device).await {
+            Ok(Some(item)) => item,
+            Ok(None) => return,
+            Err(e) => {
+                error!("failed to read queued items: {}", e);
+                return;
+            }
+        };
+        info!(id = item.id, "delivering queued item");
+        ctx.emit(DELIVERY, Delivery::new(&item, DeliveryState::Sending));
+        let res = match item.content.clone() {
+            QueuedContent::Text {
+                contents,
+                message_id,
+            } => send_text(&ctx, &channel, contents, message_id)
+                .await
+                .map_err(|e| human_readable_error(&e)),
+            QueuedContent::Files { paths, assoc_text } => {
+                send_files_to(&ctx, &channel, paths, assoc_text).await
+            }
+            QueuedContent::Directory { path, assoc_text } => {
+                send_directory_to(&ctx, &channel, &path, assoc_text).await
+            }
+        };
+        let delivery = match res {
+            Ok(()) => Delivery::new(&item, DeliveryState::Delivered),
+            // The device went away again. The item is sent again once it is back
+            Err(_) if channel.outbox.is_closed() => {
+                ctx.emit(DELIVERY, Delivery::new(&item, DeliveryState::Queued));
+                ctx.queue.stop_delivery(&device).await;
+                return;
+            }
+            Err(e) => Delivery {
+                error: Some(e),
+                ..Delivery::new(&item, DeliveryState::Failed)
+            },
+        };
+        if let Err(e) = ctx.queue.remove(item.id).await {
+            error!("failed to remove delivered item from the queue: {}", e);
+            ctx.queue.stop_delivery(&device).await;
+            return;
+        }
+        ctx.emit(DELIVERY, delivery);
+    }
+}
+
+/// Items waiting for the device `cname` to be linked, oldest first
+pub async fn queued_items(
+    ctx: &NetworkContext,
+    cname: &str,
+) -> Result<Vec<QueuedItem>, NetworkError> {
+    ctx.queue
+        .items(&full_name(cname))
+        .await
+        .map_err(NetworkError::QueueError)
+}
+
+/// Send the text message `contents` to the device `cname`. If the device is offline, the message
+/// is queued until it is linked again. Returns the id under which the status of the message is
+/// reported
+pub async fn send_text_message(
+    ctx: &NetworkContext,
+    cname: &str,
+    contents: String,
+) -> Result<u32, NetworkError> {
+    let message_id = transfer::next_transfer_id();
+    let channel = match route(ctx, cname).await? {
+        Route::Linked(channel) => channel,
+        Route::Queued(device) => {
+            let content = QueuedContent::Text {
+                contents,
+                message_id,
+            };
+            enqueue(ctx, device, content).await?;
+            return Ok(message_id);
+        }
+    };
+    send_text(ctx, &channel, contents, message_id).await?;
+    Ok(message_id)
+}
+
+async fn send_text(
+    ctx: &NetworkContext,
+    channel: &LinkedChannel,
+    contents: String,
+    message_id: u32,
+) -> Result<(), CommunicationError> {
+    let entry = HistoryEntry::new(
+        channel.device.clone(),
+        Direction::Outgoing,
+        TransferKind::Text,
+        contents.clone(),
+        contents.len() as u64,
+        TransferStatus::Completed,
+    );
+    let mut receipt = MessageReceipt {
+        message_id,
+        device: channel.device.clone(),
+        status: MessageStatus::Sent,
+    };
+    let message = protocol::protobuf::TextMessage {
+        contents,
+        message_id,
+    };
+    let encmsg = protocol::encode(TransferType::TextMessage, message);
+    channel.pending.expect_ack(message_id);
+    if let Err(e) = channel.outbox.send(encmsg).await {
+        channel.pending.acknowledge(message_id);
+        receipt.status = MessageStatus::Failed;
+        ctx.emit(MESSAGE_RECEIPT, receipt);
+        return Err(e);
+    }
+    ctx.emit(MESSAGE_RECEIPT, receipt);
+    ctx.history.record(entry).await;
+    Ok(())
+}
+
+/// Send the files at `file_paths` to the device `cname`. If the device is offline, they are
+/// offered once it is linked again.
+///
+/// A file the device already received part of is resumed from where it stopped. Files whose
+/// transfer was cut off by a dropped link are not sent again by themselves though, they resume
+/// once they are sent again
+pub async fn send_files(
+    ctx: &NetworkContext,
+    cname: &str,
+    file_paths: Vec<PathBuf>,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let channel = match route(ctx, cname).await.map_err(NetworkError::from)? {
+        Route::Linked(channel) => channel,
+        Route::Queued(device) => {
+            let content = QueuedContent::Files {
+                paths: file_paths,
+                assoc_text,
+            };
+            return Ok(enqueue(ctx, device, content).await?);
+        }
+    };
+    send_files_to(ctx, &channel, file_paths, assoc_text).await
+}
+
+async fn send_files_to(
+    ctx: &NetworkContext,
+    channel: &LinkedChannel,
+    file_paths: Vec<PathBuf>,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let mut join_set = JoinSet::new();
+
+    for file_path in file_paths {
+        let ctx = ctx.clone();
+        let channel = channel.clone();
+        let assoc_text = assoc_text.clone();
+        join_set.spawn(async move {
+            let file_name = file_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| format!("{} is not a valid file path", file_path.display()))?
+                .to_string();
+            let res = send_file(
+                &ctx,
+                &channel,
+                &file_path,
+                file_name.clone(),
+                assoc_text.clone(),
+                None,
+            )
+            .await;
+            let size = match res {
+                Ok(size) => size,
+                Err(_) => tokio::fs::metadata(&file_path)
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or(0),
+            };
+            let entry = HistoryEntry {
+                assoc_text,
+                path: Some(file_path),
+                ..HistoryEntry::new(
+                    channel.device.clone(),
+                    Direction::Outgoing,
+                    TransferKind::File,
+                    file_name,
+                    size,
+                    TransferStatus::of(&res),
+                )
+            };
+            ctx.history.record(entry).await;
+            res.map_err(|e| human_readable_error(&e))
+        });
+    }
+
+    for res in join_set.join_all().await {
+        res?;
+    }
+
+    Ok(())
+}
+
+/// Send the directory at `dir_path` to the device `cname`. If the device is offline, it is
+/// offered once the device is linked again
+pub async fn send_directory(
+    ctx: &NetworkContext,
+    cname: &str,
+    dir_path: &Path,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let channel = match route(ctx, cname).await.map_err(NetworkError::from)? {
+        Route::Linked(channel) => channel,
+        Route::Queued(device) => {
+            let content = QueuedContent::Directory {
+                path: dir_path.to_path_buf(),
+                assoc_text,
+            };
+            return Ok(enqueue(ctx, device, content).await?);
+        }
+    };
+    send_directory_to(ctx, &channel, dir_path, assoc_text).await
+}
+
+async fn send_directory_to(
+    ctx: &NetworkContext,
+    channel: &LinkedChannel,
+    dir_path: &Path,
+    assoc_text: Option<String>,
+) -> Result<(), String> {
+    let name = dir_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("{} is not a valid directory path", dir_path.display()))?
+        .to_string();
+    let entries = transfer::walk_directory(dir_path)
+        .await
+        .map_err(|e| human_readable_error(&e))?;
+    let files = entries.iter().filter(|e| !e.directory);
+    let progress = DirectoryProgress {
+        directory_id: transfer::next_transfer_id(),
+        name: name.clone(),
+        files_done: 0,
+        file_count: files.clone().count() as u32,
+        bytes_done: 0,
+        total_size: files.map(|e| e.size).sum(),
+    };
+    let total_size = progress.total_size;
+
+    let res = send_directory_entries(
+        ctx,
+        channel,
+        dir_path,
+        entries,
+        progress,
+        assoc_text.clone(),
+    )
+    .await;
+    let entry = HistoryEntry {
+        assoc_text,
+        path: Some(dir_path.to_path_buf()),
+        ..HistoryEntry::new(
+            channel.device.clone(),
+            Direction::Outgoing,
+            TransferKind::Directory,
+            name,
+            total_size,
+            TransferStatus::of(&res),
+        )
+    };
+    ctx.history.record(entry).await;
+    res.map_err(|e| human_readable_error(&e))
+}
+
+/// Offer the directory at `dir_path` to the peer and send its `entries` once it is accepted
+async fn send_directory_entries(
+    ctx: &NetworkContext,
+    channel: &LinkedChannel,
+    dir_path: &Path,
+    entries: Vec<protocol::ManifestEntry>,
+    mut progress: DirectoryProgress,
+    assoc_text: Option<String>,
+) -> Result<(), CommunicationError> {
+    let directory_id = progress.directory_id;
+    let transfer = protocol::PrepareDirectoryTransfer {
+        directory_id,
+        name: progress.name.clone(),
+        total_size: progress.total_size,
+        file_count: progress.file_count,
+        assoc_text,
+    };
+    let enctransfer = protocol::encode(TransferType::PrepareDirectoryTransfer, transfer);
+    let mut outgoing = channel.pending.register(directory_id);
+    channel.outbox.send(enctransfer).await?;
+    wait_for_acceptance(&mut outgoing).await?;
+    ctx.emit(DIRECTORY_PROGRESS, &progress);
+
+    // Send the manifest in batches so that each message stays within the payload limit
+    let mut manifest = protocol::DirectoryManifest {
+        directory_id,
+        entries: Vec::new(),
+    };
+    for entry in &entries {
+        manifest.entries.push(entry.clone());
+        if manifest.encoded_len() > FILE_CHUNK_SIZE {
+            let encmanifest = protocol::encode(TransferType::DirectoryManifest, manifest);
+            channel.outbox.send(encmanifest).await?;
+            manifest = protocol::DirectoryManifest {
+                directory_id,
+                entries: Vec::new(),
+            };
+        }
+    }
+    if !manifest.entries.is_empty() {
+        let encmanifest = protocol::encode(TransferType::DirectoryManifest, manifest);
+        channel.outbox.send(encmanifest).await?;
+    }
+
+    for entry in entries.into_iter().filter(|e| !e.directory) {
+        if let Err(err) = outgoing_stopped(outgoing.state()) {
+            info!(?dir_path, "stopped sending directory: {err}");
+            return Err(err);
+        }
+        let file_path = dir_path.join(transfer::relative_path(&entry.path).unwrap());
+        let size = send_file(
+            ctx,
+            channel,
+            &file_path,
+            entry.path,
+            None,
+            Some(directory_id),
+        )
+        .await?;
+        progress.files_done += 1;
+        progress.bytes_done += size;
+        ctx.emit(DIRECTORY_PROGRESS, &progress);
+    }
+    info!(?dir_path, "sent directory to peer");
+
+    Ok(())
+}
+
+/// Transfers exchanged with the device `cname`, most recent first. `offset` entries are skipped
+/// and at most `limit` are returned. The device can also be given by its instance name
+pub async fn transfer_history(
+    ctx: &NetworkContext,
+    cname: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<HistoryEntry>, NetworkError> {
+    ctx.history
+        .page(&full_name(cname), offset, limit)
+        .await
+        .map_err(NetworkError::HistoryError)
+}
+
+/// Same as [`transfer_history`], but reads the history kept in `data_dir` without a
+/// [`NetworkContext`], so nothing has to be started to look at past transfers
+pub async fn read_transfer_history(
+    data_dir: &Path,
+    cname: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<HistoryEntry>, NetworkError> {
+    History::new(data_dir)
+        .page(&full_name(cname), offset, limit)
+        .await
+        .map_err(NetworkError::HistoryError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use harness::{test_context, TestFrontend};
+    use std::net::Ipv4Addr;
+
+    /// Make the device `name` with the identity `public_key` known to the connection manager, as
+    /// if it was discovered over mDNS
+    async fn discover(ctx: &NetworkContext, name: &str, public_key: Option<ed25519::PublicKey>) {
+        let full_name = name.to_string() + "." + MDNS_SERVICE_TYPE;
+        let con = Connection {
+            info: ConnectionInfo {
+                name: full_name.clone(),
+                linked: false,
+                state: ConnectionState::Offline,
+                platform: None,
+            },
+            addresses: Vec::new(),
+            port: fdrop_config::DEFAULT_PORT,
+            outbox: None,
+            pending: PendingTransfers::default(),
+            public_key,
+            added_by_address: false,
+        };
+        ctx.connection_manager
+            .lock()
+            .await
+            .available_connections
+            .insert(full_name, con);
+    }
+
+    fn loopback() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, fdrop_config::DEFAULT_PORT))
+    }
+
+    /// Establish a secure channel over loopback. Returns the initiating and the accepting side
+    async fn secure_pair(
+        initiator: &ed25519::Keypair,
+        responder: &ed25519::Keypair,
+    ) -> (SecureStream, SecureStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = responder.clone();
+        let accepted = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            secure::handshake(stream, &responder, false).await.unwrap()
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let initiated = secure::handshake(stream, initiator, true).await.unwrap();
+        (initiated, accepted.await.unwrap())
+    }
+
+    /// Send a link request as the device `name` and return the response of the peer
+    async fn request_link(
+        stream: &mut SecureStream,
+        keypair: &ed25519::Keypair,
+        name: &str,
+    ) -> LinkResponse {
+        let message = protocol::Link {
+            request: Some(true),
+            name: name.to_string(),
+            platform: String::from(OUR_PLATFORM),
+            response: None,
+            public_key: keypair.public().to_bytes().to_vec(),
+            signature: stream.sign_link(keypair),
+            port: None,
+        };
+        let link_message = protocol::encode(TransferType::Link, message);
+        stream.write_message(&link_message).await.unwrap();
+        let (ttype, payload) = stream.read_message().await.unwrap();
+        assert_eq!(ttype, TransferType::Link);
+        let resp = protocol::Link::decode(payload).unwrap();
+        LinkResponse::try_from(resp.response.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_agrees_on_verification_code() {
+        let client = ed25519::Keypair::generate();
+        let server = ed25519::Keypair::generate();
+        let (initiated, accepted) = secure_pair(&client, &server).await;
+
+        assert_eq!(initiated.verification_code(), accepted.verification_code());
+        assert_eq!(initiated.peer_identity, server.public());
+        assert_eq!(accepted.peer_identity, client.public());
+    }
+
+    #[tokio::test]
+    async fn accepted_link_request_trusts_device() {
+        let (ctx, _) = test_context("server", LinkResponse::Accepted);
+        let client = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        discover(&ctx, "client", Some(client.public())).await;
+        let (mut initiated, mut accepted) = secure_pair(&client, &server).await;
+
+        let ctx2 = ctx.clone();
+        let authenticated =
+            tokio::spawn(async move { authenticate_peer(&mut accepted, loopback(), &ctx2).await });
+        let resp = request_link(&mut initiated, &client, "client").await;
+        let (_, full_name) = authenticated.await.unwrap().unwrap().unwrap();
+
+        assert_eq!(resp, LinkResponse::Accepted);
+        assert_eq!(full_name, "client.".to_string() + MDNS_SERVICE_TYPE);
+        assert!(fdrop_config::is_trusted_device(
+            &ctx.data_dir,
+            &hex::encode(client.public().to_bytes())
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_link_request_is_not_trusted() {
+        let (ctx, _) = test_context("server", LinkResponse::Rejected);
+        let client = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        discover(&ctx, "client", None).await;
+        let (mut initiated, mut accepted) = secure_pair(&client, &server).await;
+
+        let ctx2 = ctx.clone();
+        let authenticated =
+            tokio::spawn(async move { authenticate_peer(&mut accepted, loopback(), &ctx2).await });
+        let resp = request_link(&mut initiated, &client, "client").await;
+
+        assert_eq!(resp, LinkResponse::Rejected);
+        assert!(authenticated.await.unwrap().unwrap().is_none());
+        assert!(!fdrop_config::is_trusted_device(
+            &ctx.data_dir,
+            &hex::encode(client.public().to_bytes())
+        ));
+    }
+
+    #[tokio::test]
+    async fn link_request_from_blocked_device_is_refused_without_asking() {
+        let (ctx, frontend) = test_context("server", LinkResponse::Accepted);
+        let client = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        let client_key = hex::encode(client.public().to_bytes());
+        ctx.config
+            .lock()
+            .await
+            .blocked
+            .push(fdrop_config::BlockedDevice {
+                name: "client".to_string(),
+                public_key: Some(client_key.clone()),
+            });
+        discover(&ctx, "client", None).await;
+        let (mut initiated, mut accepted) = secure_pair(&client, &server).await;
+
+        let ctx2 = ctx.clone();
+        let authenticated =
+            tokio::spawn(async move { authenticate_peer(&mut accepted, loopback(), &ctx2).await });
+        let resp = request_link(&mut initiated, &client, "client").await;
+
+        assert_eq!(resp, LinkResponse::Rejected);
+        assert!(authenticated.await.unwrap().unwrap().is_none());
+        assert_eq!(frontend.link_requests(), 0);
+        assert!(!fdrop_config::is_trusted_device(&ctx.data_dir, &client_key));
+    }
+
+    #[tokio::test]
+    async fn blocking_link_request_blocks_device() {
+        let (ctx, _) = test_context("server", LinkResponse::Blocked);
+        let client = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        discover(&ctx, "client", None).await;
+        let (mut initiated, mut accepted) = secure_pair(&client, &server).await;
+
+        let ctx2 = ctx.clone();
+        let authenticated =
+            tokio::spawn(async move { authenticate_peer(&mut accepted, loopback(), &ctx2).await });
+        let resp = request_link(&mut initiated, &client, "client").await;
+
+        assert_eq!(resp, LinkResponse::Rejected);
+        assert!(authenticated.await.unwrap().unwrap().is_none());
+        let config = fdrop_config::read_config(&ctx.data_dir).unwrap();
+        assert_eq!(
+            config.blocked,
+            vec![fdrop_config::BlockedDevice {
+                name: "client".to_string(),
+                public_key: Some(hex::encode(client.public().to_bytes())),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn link_request_from_impersonator_is_refused() {
+        let (ctx, _) = test_context("server", LinkResponse::Accepted);
+        let client = ed25519::Keypair::generate();
+        let impersonator = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        discover(&ctx, "client", Some(client.public())).await;
+        let (mut initiated, mut accepted) = secure_pair(&impersonator, &server).await;
+
+        let ctx2 = ctx.clone();
+        let authenticated =
+            tokio::spawn(async move { authenticate_peer(&mut accepted, loopback(), &ctx2).await });
+        let message = protocol::Link {
+            request: Some(true),
+            name: "client".to_string(),
+            platform: String::from(OUR_PLATFORM),
+            response: None,
+            public_key: impersonator.public().to_bytes().to_vec(),
+            signature: initiated.sign_link(&impersonator),
+            port: None,
+        };
+        let link_message = protocol::encode(TransferType::Link, message);
+        initiated.write_message(&link_message).await.unwrap();
+
+        assert!(matches!(
+            authenticated.await.unwrap(),
+            Err(CommunicationError::IdentityMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn text_message_is_emitted_and_acknowledged() {
+        let (ctx, frontend) = test_context("server", LinkResponse::Accepted);
+        let outbox = Outbox::default();
+        let message = protocol::TextMessage {
+            contents: "hello".to_string(),
+            message_id: 5,
+        };
+
+        transfer_handler(
+            TransferType::TextMessage,
+            Bytes::from(message.encode_to_vec()),
+            &ctx,
+            &outbox,
+            &mut Incoming::new(
+                String::new(),
+                "client".to_string(),
+                [0; 32],
+                flume::unbounded().0,
+            ),
+            &PendingTransfers::default(),
+        )
+        .await;
+
+        let transfer = frontend.next_event(TRANSFER).await;
+        assert_eq!(transfer["display_content"], "hello");
+        let (ttype, payload) = read_stream(&mut &outbox.next().await[..]).await.unwrap();
+        assert_eq!(ttype, TransferType::TextMessageAck);
+        let ack = protocol::TextMessageAck::decode(payload).unwrap();
+        assert_eq!(ack.message_id, 5);
+    }
+
+    /// Context of a peer that is receiving the 10 byte file with transfer id 7 from "client".
+    /// Returns the state of the incoming stream and the path of the partial file
+    async fn incoming_fixture() -> (NetworkContext, Arc<TestFrontend>, Incoming, PathBuf) {
+        let (ctx, frontend) = test_context("server", LinkResponse::Accepted);
+        let path = ctx.data_dir.join("data.bin");
+        let partial_path = transfer::partial_path(&ctx.data_dir, &path, 10);
+        let file = open_partial_file(&partial_path, 10).await.unwrap();
+        let progress = ProgressTracker::new(
+            &ctx.transfers,
+            7,
+            "client".to_string(),
+            Direction::Incoming,
+            "data.bin".to_string(),
+            0,
+            10,
+        );
+        let mut incoming = Incoming::new(
+            String::new(),
+            "client".to_string(),
+            [0; 32],
+            flume::unbounded().0,
+        );
+        incoming.files.insert(
+            7,
+            IncomingFile {
+                file,
+                path,
+                partial_path: partial_path.clone(),
+                size: 10,
+                received: 0,
+                hash: Vec::new(),
+                directory_id: None,
+                assoc_text: None,
+                progress,
+            },
+        );
+        (ctx, frontend, incoming, partial_path)
+    }
+
+    #[tokio::test]
+    async fn cancelled_incoming_file_is_deleted() {
+        let (ctx, frontend, mut incoming, partial_path) = incoming_fixture().await;
+
+        assert!(cancel_incoming(&ctx, &mut incoming, 7).await);
+        assert!(!partial_path.exists());
+        assert!(active_transfers(&ctx).is_empty());
+        let cancelled = frontend.next_event(TRANSFER_CANCELLED).await;
+        assert_eq!(cancelled["transfer_id"], 7);
+        assert!(!cancel_incoming(&ctx, &mut incoming, 7).await);
+    }
+
+    #[tokio::test]
+    async fn chunk_beyond_announced_size_fails_transfer() {
+        let (ctx, frontend, mut incoming, partial_path) = incoming_fixture().await;
+        let outbox = Outbox::default();
+        let data = vec![0u8; 11];
+        let message = protocol::FileChunk {
+            transfer_id: 7,
+            checksum: blake3::hash(&data).as_bytes().to_vec(),
+            data,
+        };
+
+        transfer_handler(
+            TransferType::FileChunk,
+            Bytes::from(message.encode_to_vec()),
+            &ctx,
+            &outbox,
+            &mut incoming,
+            &PendingTransfers::default(),
+        )
+        .await;
+
+        assert!(incoming.files.is_empty());
+        assert!(!partial_path.exists());
+        let failed = frontend.next_event(TRANSFER_FAILED).await;
+        assert_eq!(failed["transfer_id"], 7);
+        let (ttype, _) = read_stream(&mut &outbox.next().await[..]).await.unwrap();
+        assert_eq!(ttype, TransferType::FileTransferFailed);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let outbox = Outbox::default();
+        let text = protocol::TextMessage {
+            contents: "a".repeat(MAX_PAYLOAD_SIZE + 1),
+            message_id: 0,
+        };
+        let res = outbox
+            .send(protocol::encode(TransferType::TextMessage, text))
+            .await;
+        assert!(matches!(res, Err(CommunicationError::MessageTooLarge)));
+
+        let mut header = vec![TransferType::TextMessage as u8];
+        header.extend_from_slice(&protocol::CONTROL_STREAM.to_be_bytes());
+        header.extend_from_slice(&(MAX_PAYLOAD_SIZE as u16 + 1).to_be_bytes());
+        let res = read_stream(&mut header.as_slice()).await;
+        assert!(matches!(res, Err(CommunicationError::DecodeError)));
+    }
+
+    #[tokio::test]
+    async fn outbox_interleaves_transfers() {
+        let outbox = Outbox::default();
+        let chunk = |stream| {
+            let end = protocol::FileTransferEnd {
+                transfer_id: stream,
+            };
+            protocol::encode_stream(TransferType::FileTransferEnd, stream, end)
+        };
+        for _ in 0..3 {
+            outbox.send(chunk(1)).await.unwrap();
+        }
+        outbox.send(chunk(2)).await.unwrap();
+        let text = protocol::TextMessage {
+            contents: "hello".to_string(),
+            message_id: 0,
+        };
+        outbox
+            .send(protocol::encode(TransferType::TextMessage, text))
+            .await
+            .unwrap();
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(protocol::frame_stream(&outbox.next().await));
+        }
+        assert_eq!(order, [0, 1, 2, 1, 1]);
+    }
+
+    /// Serve the stream of the linked device "client" on `ctx`. Returns the end of the stream
+    /// held by the device, its full name and the outbox of its connection
+    async fn serve_linked_client(ctx: &NetworkContext) -> (SecureStream, String, Outbox) {
+        let client = ed25519::Keypair::generate();
+        let server = ctx.connection_manager.lock().await.keypair.clone().unwrap();
+        discover(ctx, "client", Some(client.public())).await;
+        let (initiated, accepted) = secure_pair(&client, &server).await;
+        let full_name = "client.".to_string() + MDNS_SERVICE_TYPE;
+        let outbox = Outbox::default();
+        {
+            let mut connection_manager = ctx.connection_manager.lock().await;
+            let con = connection_manager.get_connection_mut(&full_name).unwrap();
+            con.outbox = Some(outbox.clone());
+            con.info.set_state(ConnectionState::Linked);
+        }
+        tokio::spawn(handle_postauth_stream(
+            accepted,
+            outbox.clone(),
+            PendingTransfers::default(),
+            full_name.clone(),
+            ctx.clone(),
+        ));
+        (initiated, full_name, outbox)
+    }
+
+    #[tokio::test]
+    async fn closed_stream_disconnects_device() {
+        let (ctx, frontend) = test_context("server", LinkResponse::Accepted);
+        let (mut initiated, full_name, outbox) = serve_linked_client(&ctx).await;
+
+        let ping = protocol::encode(TransferType::Ping, protocol::Ping {});
+        initiated.write_message(&ping).await.unwrap();
+        // The handler may ping first
+        loop {
+            let (ttype, _) = initiated.read_message().await.unwrap();
+            if ttype == TransferType::Pong {
+                break;
+            }
+        }
+        drop(initiated);
+
+        let disconnected = frontend.next_event(DEVICE_DISCONNECTED).await;
+        assert_eq!(disconnected["name"], full_name.as_str());
+        assert_eq!(disconnected["state"], "offline");
+        let mut connection_manager = ctx.connection_manager.lock().await;
+        let con = connection_manager.get_connection_mut(&full_name).unwrap();
+        assert!(con.outbox.is_none());
+        assert!(!con.info.linked);
+        assert!(matches!(
+            outbox.send(ping).await,
+            Err(CommunicationError::Disconnected)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_device_is_disconnected() {
+        let (ctx, frontend) = test_context("server", LinkResponse::Accepted);
+        // The connection stays open, but the device never answers the pings
+        let (_initiated, full_name, _) = serve_linked_client(&ctx).await;
+
+        tokio::time::sleep(HEARTBEAT_TIMEOUT).await;
+        let disconnected = frontend.next_event(DEVICE_DISCONNECTED).await;
+        assert_eq!(disconnected["name"], full_name.as_str());
+        assert_eq!(disconnected["state"], "offline");
+        let connection_manager = ctx.connection_manager.lock().await;
+        let con = connection_manager.get_connection(&full_name).unwrap();
+        assert!(con.outbox.is_none());
     }
 }
@@ -0,0 +1,132 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{
+    errors::CommunicationError,
+    protocol::{self, CONTROL_STREAM},
+};
+
+/// Number of frames of a single transfer that can wait to be written. Senders of bulk data wait
+/// for room once their queue is full, which keeps a fast disk from buffering a whole file
+const STREAM_QUEUE_LEN: usize = 16;
+
+/// Messages waiting to be written to a linked device
+///
+/// Every frame carries the stream it belongs to. Frames of the control stream (text messages,
+/// responses, offers) are written before anything else. The data of transfers is written one
+/// frame per transfer in turn, so that a large file neither blocks messages nor other files.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Outbox {
+    queues: Arc<Mutex<Queues>>,
+    /// Notified when a frame is queued
+    queued: Arc<Notify>,
+    /// Notified when a frame of a transfer is taken out of its queue
+    written: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    control: VecDeque<Bytes>,
+    /// Transfers with frames waiting, in the order in which they get their next turn
+    streams: VecDeque<(u32, VecDeque<Bytes>)>,
+    /// Set once the stream to the device is gone
+    closed: bool,
+}
+
+impl Outbox {
+    /// Queue a frame created by [`protocol::encode`] or [`protocol::encode_stream`]
+    pub async fn send(&self, frame: Bytes) -> Result<(), CommunicationError> {
+        protocol::check_size(&frame)?;
+        let stream = protocol::frame_stream(&frame);
+        loop {
+            // Registered before checking for room so that a frame written in between is not missed
+            let written = self.written.notified();
+            {
+                let mut queues = self.queues.lock().unwrap();
+                if queues.closed {
+                    return Err(CommunicationError::Disconnected);
+                }
+                if stream == CONTROL_STREAM {
+                    queues.control.push_back(frame);
+                    break;
+                }
+                match queues.streams.iter_mut().find(|(id, _)| *id == stream) {
+                    Some((_, frames)) if frames.len() >= STREAM_QUEUE_LEN => {}
+                    Some((_, frames)) => {
+                        frames.push_back(frame);
+                        break;
+                    }
+                    None => {
+                        queues.streams.push_back((stream, VecDeque::from([frame])));
+                        break;
+                    }
+                }
+            }
+            written.await;
+        }
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the next frame to write. Cancel safe, a frame is only taken out of the queues
+    /// when it is returned
+    pub async fn next(&self) -> Bytes {
+        loop {
+            let queued = self.queued.notified();
+            if let Some(frame) = self.pop() {
+                return frame;
+            }
+            queued.await;
+        }
+    }
+
+    /// Drop the frames of `stream` that have not been written yet
+    pub fn discard(&self, stream: u32) {
+        self.queues
+            .lock()
+            .unwrap()
+            .streams
+            .retain(|(id, _)| *id != stream);
+        self.written.notify_waiters();
+    }
+
+    /// Drop everything that has not been written yet and refuse new frames. Senders waiting for
+    /// room are woken up
+    pub fn close(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.closed = true;
+        queues.control.clear();
+        queues.streams.clear();
+        drop(queues);
+        self.written.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queues.lock().unwrap().closed
+    }
+
+    /// Whether `other` queues frames for the same stream as this outbox
+    pub fn same_as(&self, other: &Outbox) -> bool {
+        Arc::ptr_eq(&self.queues, &other.queues)
+    }
+
+    fn pop(&self) -> Option<Bytes> {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(frame) = queues.control.pop_front() {
+            return Some(frame);
+        }
+        let (stream, mut frames) = queues.streams.pop_front()?;
+        let frame = frames.pop_front();
+        if !frames.is_empty() {
+            queues.streams.push_back((stream, frames));
+        }
+        drop(queues);
+        self.written.notify_waiters();
+        frame
+    }
+}
@@ -6,12 +6,26 @@ use bytes::{BufMut, Bytes, BytesMut};
 use prost::Message;
 pub use protobuf::*;
 
+use crate::{errors::CommunicationError, MAX_PAYLOAD_SIZE};
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Clone, Copy, serde::Serialize)]
 pub enum TransferType {
     Link = 1 << 7,
     PrepareFileTransfer = 0x02,
     TextMessage = 0x01,
+    FileChunk = 0x03,
+    FileTransferEnd = 0x04,
+    FileTransferComplete = 0x05,
+    FileTransferResponse = 0x06,
+    FileTransferFailed = 0x07,
+    PrepareDirectoryTransfer = 0x08,
+    DirectoryManifest = 0x09,
+    CancelTransfer = 0x0A,
+    Ping = 0x0B,
+    Pong = 0x0C,
+    TextMessageAck = 0x0D,
+    Unlink = 0x0E,
 }
 
 impl TryFrom<u8> for TransferType {
@@ -21,6 +35,18 @@ impl TryFrom<u8> for TransferType {
             128 => Ok(Self::Link),
             1 => Ok(Self::TextMessage),
             2 => Ok(Self::PrepareFileTransfer),
+            3 => Ok(Self::FileChunk),
+            4 => Ok(Self::FileTransferEnd),
+            5 => Ok(Self::FileTransferComplete),
+            6 => Ok(Self::FileTransferResponse),
+            7 => Ok(Self::FileTransferFailed),
+            8 => Ok(Self::PrepareDirectoryTransfer),
+            9 => Ok(Self::DirectoryManifest),
+            10 => Ok(Self::CancelTransfer),
+            11 => Ok(Self::Ping),
+            12 => Ok(Self::Pong),
+            13 => Ok(Self::TextMessageAck),
+            14 => Ok(Self::Unlink),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "invalid value given to convert to message type",
@@ -29,11 +55,39 @@ impl TryFrom<u8> for TransferType {
     }
 }
 
+/// Stream of the messages that do not carry the data of a transfer
+pub(crate) const CONTROL_STREAM: u32 = 0;
+/// Size of the header in front of every message: the message type, the stream and the length
+pub(crate) const HEADER_SIZE: usize = 7;
+
+/// Encode a message on the control stream
 pub(crate) fn encode(mtype: TransferType, message: impl Message) -> Bytes {
+    encode_stream(mtype, CONTROL_STREAM, message)
+}
+
+/// Encode a message carrying the data of the transfer `stream`. The messages of every stream
+/// arrive in order, but they may be interleaved with those of other streams
+pub(crate) fn encode_stream(mtype: TransferType, stream: u32, message: impl Message) -> Bytes {
     let mut buf = BytesMut::with_capacity(1024);
     buf.put_u8(mtype as u8);
-    let length = message.encoded_len() as u16;
+    buf.put_u32(stream);
+    // Messages whose length does not fit in the header are refused by `check_size` before they
+    // are sent
+    let length = u16::try_from(message.encoded_len()).unwrap_or(u16::MAX);
     buf.put_u16(length);
     message.encode(&mut buf).unwrap();
     buf.freeze()
 }
+
+/// Make sure that a frame created by [`encode_stream`] is small enough for the peer to accept it
+pub(crate) fn check_size(frame: &[u8]) -> Result<(), CommunicationError> {
+    if frame.len() - HEADER_SIZE > MAX_PAYLOAD_SIZE {
+        return Err(CommunicationError::MessageTooLarge);
+    }
+    Ok(())
+}
+
+/// Stream of a message created by [`encode_stream`]
+pub(crate) fn frame_stream(frame: &[u8]) -> u32 {
+    u32::from_be_bytes(frame[1..5].try_into().unwrap())
+}
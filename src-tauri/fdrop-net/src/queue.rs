@@ -0,0 +1,187 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Mutex;
+
+const QUEUE_FILE: &str = "offline-queue.json";
+
+/// What is delivered to a device once it is linked again
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum QueuedContent {
+    Text {
+        contents: String,
+        message_id: u32,
+    },
+    Files {
+        paths: Vec<PathBuf>,
+        assoc_text: Option<String>,
+    },
+    Directory {
+        path: PathBuf,
+        assoc_text: Option<String>,
+    },
+}
+
+/// A text message or file offer for a device that was offline when it was sent
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct QueuedItem {
+    pub id: u64,
+    /// Name of the device the item is for
+    pub device: String,
+    /// Seconds since the Unix epoch at which the item was queued
+    pub timestamp: u64,
+    pub content: QueuedContent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryState {
+    /// Waiting for the device to be linked
+    Queued,
+    Sending,
+    Delivered,
+    /// The device is linked but the item could not be sent. It is not tried again
+    Failed,
+}
+
+/// Change in the delivery of a queued item
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub device: String,
+    pub state: DeliveryState,
+    pub error: Option<String>,
+}
+
+impl Delivery {
+    pub(crate) fn new(item: &QueuedItem, state: DeliveryState) -> Self {
+        Self {
+            id: item.id,
+            device: item.device.clone(),
+            state,
+            error: None,
+        }
+    }
+}
+
+/// Items waiting for their devices to be linked, kept in the data folder so that they survive a
+/// restart. The items of a device are delivered in the order in which they were queued.
+#[derive(Clone, Debug)]
+pub(crate) struct OfflineQueue {
+    path: PathBuf,
+    /// Devices whose items are being delivered. Also keeps the file from being changed by two
+    /// tasks at the same time
+    delivering: Arc<Mutex<HashSet<String>>>,
+}
+
+impl OfflineQueue {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join(QUEUE_FILE),
+            delivering: Arc::default(),
+        }
+    }
+
+    pub async fn push(
+        &self,
+        device: String,
+        content: QueuedContent,
+    ) -> std::io::Result<QueuedItem> {
+        let _delivering = self.delivering.lock().await;
+        let mut items = self.read().await?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let item = QueuedItem {
+            id: items.iter().map(|i| i.id + 1).max().unwrap_or(0),
+            device,
+            timestamp,
+            content,
+        };
+        items.push(item.clone());
+        self.write(&items).await?;
+        Ok(item)
+    }
+
+    /// Items waiting for the device `device`, oldest first
+    pub async fn items(&self, device: &str) -> std::io::Result<Vec<QueuedItem>> {
+        let _delivering = self.delivering.lock().await;
+        let mut items = self.read().await?;
+        items.retain(|i| i.device == device);
+        Ok(items)
+    }
+
+    /// Whether anything is waiting for the device `device`. New items for it must then be queued
+    /// as well to keep them in order
+    pub async fn is_waiting(&self, device: &str) -> bool {
+        self.items(device)
+            .await
+            .is_ok_and(|items| !items.is_empty())
+    }
+
+    pub async fn remove(&self, id: u64) -> std::io::Result<()> {
+        let _delivering = self.delivering.lock().await;
+        let mut items = self.read().await?;
+        items.retain(|i| i.id != id);
+        self.write(&items).await
+    }
+
+    /// Drop the items waiting for the device `device`
+    pub async fn clear(&self, device: &str) -> std::io::Result<()> {
+        let _delivering = self.delivering.lock().await;
+        let mut items = self.read().await?;
+        items.retain(|i| i.device != device);
+        self.write(&items).await
+    }
+
+    /// Mark the items of `device` as being delivered. Returns `false` if that was the case
+    /// already
+    pub async fn start_delivery(&self, device: &str) -> bool {
+        self.delivering.lock().await.insert(device.to_string())
+    }
+
+    /// Next item to deliver to `device`. Once there are none left, delivery ends. Checking for
+    /// the last item and ending delivery happen at once so that an item queued meanwhile is
+    /// either returned here or starts a new delivery.
+    pub async fn next(&self, device: &str) -> std::io::Result<Option<QueuedItem>> {
+        let mut delivering = self.delivering.lock().await;
+        let item = match self.read().await {
+            Ok(items) => items.into_iter().find(|i| i.device == device),
+            Err(e) => {
+                delivering.remove(device);
+                return Err(e);
+            }
+        };
+        if item.is_none() {
+            delivering.remove(device);
+        }
+        Ok(item)
+    }
+
+    /// Stop delivering to `device`, leaving its remaining items queued
+    pub async fn stop_delivery(&self, device: &str) {
+        self.delivering.lock().await.remove(device);
+    }
+
+    async fn read(&self) -> std::io::Result<Vec<QueuedItem>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the file in one step so that a crash cannot leave it half written
+    async fn write(&self, items: &[QueuedItem]) -> std::io::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(items).unwrap()).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await
+    }
+}
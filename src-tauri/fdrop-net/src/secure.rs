@@ -0,0 +1,299 @@
+//! Encrypted channel between two peers.
+//!
+//! The channel is set up with the `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake. Every connection
+//! uses a freshly generated Noise static key, which is bound to the ed25519 identity of the device
+//! by sending the identity key along with a signature over the static key in the handshake
+//! payloads:
+//!
+//! ```text
+//! initiator -> responder: e
+//! responder -> initiator: e, ee, s, es, {identity_r, sign_r(STATIC_KEY_CONTEXT || s_r)}
+//! initiator -> responder: s, se, {identity_i, sign_i(STATIC_KEY_CONTEXT || s_i)}
+//! ```
+//!
+//! Handshake and transport messages are both sent as a 16-bit length followed by the Noise
+//! message. Each transport message carries one message in the usual plain text format.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use libp2p::identity::ed25519;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use snow::{HandshakeState, StatelessTransportState};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+use tracing::error;
+
+use crate::{
+    errors::CommunicationError,
+    protocol::{self, TransferType},
+    read_stream,
+};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const STATIC_KEY_CONTEXT: &[u8] = b"fdrop noise static key";
+const LINK_CONTEXT: &[u8] = b"fdrop link";
+const VERIFICATION_CONTEXT: &[u8] = b"fdrop verification code";
+/// Largest message allowed by the Noise protocol
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Read half of a [`SecureStream`]
+pub(crate) struct SecureReader {
+    inner: OwnedReadHalf,
+    transport: Arc<StatelessTransportState>,
+    counter: u64,
+}
+
+/// Write half of a [`SecureStream`]
+pub(crate) struct SecureWriter {
+    inner: OwnedWriteHalf,
+    transport: Arc<StatelessTransportState>,
+    counter: u64,
+    pub session_hash: [u8; 32],
+}
+
+/// A stream with a peer over which all messages are encrypted
+pub(crate) struct SecureStream {
+    reader: SecureReader,
+    writer: SecureWriter,
+    /// Identity key of the peer verified during the handshake
+    pub peer_identity: ed25519::PublicKey,
+    /// Hash of the handshake transcript. This is unique to every connection
+    pub session_hash: [u8; 32],
+}
+
+async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, CommunicationError> {
+    let length = stream
+        .read_u16()
+        .await
+        .map_err(CommunicationError::ReadError)?;
+    let mut frame = vec![0u8; length as usize];
+    stream
+        .read_exact(&mut frame)
+        .await
+        .map_err(CommunicationError::ReadError)?;
+    Ok(frame)
+}
+
+async fn write_frame(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> Result<(), CommunicationError> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream
+        .write_all(&frame)
+        .await
+        .map_err(CommunicationError::WriteError)
+}
+
+/// Send the next handshake message carrying `payload`
+async fn write_handshake(
+    stream: &mut TcpStream,
+    state: &mut HandshakeState,
+    payload: &[u8],
+) -> Result<(), CommunicationError> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let length = state.write_message(payload, &mut message).map_err(|e| {
+        error!("failed to write handshake message: {e}");
+        CommunicationError::HandshakeError
+    })?;
+    write_frame(stream, &message[..length]).await
+}
+
+/// Read the next handshake message and return its payload
+async fn read_handshake(
+    stream: &mut TcpStream,
+    state: &mut HandshakeState,
+) -> Result<Vec<u8>, CommunicationError> {
+    let message = read_frame(stream).await?;
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    let length = state.read_message(&message, &mut payload).map_err(|e| {
+        error!("peer sent an invalid handshake message: {e}");
+        CommunicationError::HandshakeError
+    })?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+/// Payload that binds our Noise static key to our identity
+fn identity_payload(keypair: &ed25519::Keypair, static_key: &[u8]) -> Vec<u8> {
+    protocol::Handshake {
+        identity_key: keypair.public().to_bytes().to_vec(),
+        signature: keypair.sign(&[STATIC_KEY_CONTEXT, static_key].concat()),
+    }
+    .encode_to_vec()
+}
+
+/// Verify that the identity sent in `payload` signed the static key the peer used in the handshake
+fn verify_identity(
+    state: &HandshakeState,
+    payload: &[u8],
+) -> Result<ed25519::PublicKey, CommunicationError> {
+    let message =
+        protocol::Handshake::decode(payload).map_err(|_| CommunicationError::DecodeError)?;
+    let static_key = state
+        .get_remote_static()
+        .ok_or(CommunicationError::HandshakeError)?;
+    let identity = ed25519::PublicKey::try_from_bytes(&message.identity_key)
+        .map_err(|_| CommunicationError::HandshakeError)?;
+    if !identity.verify(
+        &[STATIC_KEY_CONTEXT, static_key].concat(),
+        &message.signature,
+    ) {
+        error!("peer sent an invalid handshake signature");
+        return Err(CommunicationError::HandshakeError);
+    }
+    Ok(identity)
+}
+
+/// Sign the challenge sent in a `Link` message. The session hash is used as the challenge since it
+/// is fresh for every connection, hence the signature cannot be replayed on another connection
+pub(crate) fn sign_link(keypair: &ed25519::Keypair, session_hash: &[u8; 32]) -> Vec<u8> {
+    keypair.sign(&[LINK_CONTEXT, session_hash].concat())
+}
+
+/// Perform the handshake on `stream` and set up the encrypted channel. `initiator` must be true
+/// on the peer that opened the connection and false on the peer that accepted it
+pub(crate) async fn handshake(
+    mut stream: TcpStream,
+    keypair: &ed25519::Keypair,
+    initiator: bool,
+) -> Result<SecureStream, CommunicationError> {
+    let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap());
+    let static_key = builder
+        .generate_keypair()
+        .map_err(|_| CommunicationError::HandshakeError)?;
+    let builder = builder.local_private_key(&static_key.private);
+    let state = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+    let mut state = state.map_err(|_| CommunicationError::HandshakeError)?;
+    let payload = identity_payload(keypair, &static_key.public);
+
+    let peer_identity = if initiator {
+        write_handshake(&mut stream, &mut state, &[]).await?;
+        let theirs = read_handshake(&mut stream, &mut state).await?;
+        let peer_identity = verify_identity(&state, &theirs)?;
+        write_handshake(&mut stream, &mut state, &payload).await?;
+        peer_identity
+    } else {
+        read_handshake(&mut stream, &mut state).await?;
+        write_handshake(&mut stream, &mut state, &payload).await?;
+        let theirs = read_handshake(&mut stream, &mut state).await?;
+        verify_identity(&state, &theirs)?
+    };
+
+    let session_hash: [u8; 32] = state
+        .get_handshake_hash()
+        .try_into()
+        .map_err(|_| CommunicationError::HandshakeError)?;
+    let transport = Arc::new(
+        state
+            .into_stateless_transport_mode()
+            .map_err(|_| CommunicationError::HandshakeError)?,
+    );
+
+    let (reader, writer) = stream.into_split();
+    Ok(SecureStream {
+        reader: SecureReader {
+            inner: reader,
+            transport: transport.clone(),
+            counter: 0,
+        },
+        writer: SecureWriter {
+            inner: writer,
+            transport,
+            counter: 0,
+            session_hash,
+        },
+        peer_identity,
+        session_hash,
+    })
+}
+
+impl SecureReader {
+    /// Read and decrypt the next message from the peer
+    pub async fn read_message(&mut self) -> Result<(TransferType, Bytes), CommunicationError> {
+        let ciphertext = read_frame(&mut self.inner).await?;
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let length = self
+            .transport
+            .read_message(self.counter, &ciphertext, &mut plaintext)
+            .map_err(|_| CommunicationError::DecryptError)?;
+        self.counter += 1;
+        read_stream(&mut &plaintext[..length]).await
+    }
+}
+
+impl SecureWriter {
+    /// Encrypt and send a message created by [`protocol::encode`] to the peer
+    pub async fn write_message(&mut self, message: &[u8]) -> Result<(), CommunicationError> {
+        protocol::check_size(message)?;
+        let mut ciphertext = vec![0u8; MAX_NOISE_MESSAGE];
+        let length = self
+            .transport
+            .write_message(self.counter, message, &mut ciphertext)
+            .map_err(|_| CommunicationError::EncryptError)?;
+        self.counter += 1;
+        write_frame(&mut self.inner, &ciphertext[..length]).await
+    }
+}
+
+impl SecureStream {
+    pub async fn read_message(&mut self) -> Result<(TransferType, Bytes), CommunicationError> {
+        self.reader.read_message().await
+    }
+
+    pub async fn write_message(&mut self, message: &[u8]) -> Result<(), CommunicationError> {
+        self.writer.write_message(message).await
+    }
+
+    pub fn sign_link(&self, keypair: &ed25519::Keypair) -> Vec<u8> {
+        sign_link(keypair, &self.session_hash)
+    }
+
+    /// Verify the identity presented by the peer in a `Link` message. The key must be the one the
+    /// channel was established with and the signature must be over this connection's challenge
+    pub fn verify_link(
+        &self,
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Result<ed25519::PublicKey, CommunicationError> {
+        if public_key != self.peer_identity.to_bytes() {
+            error!("peer presented a different key than the one used for the channel");
+            return Err(CommunicationError::IdentityMismatch);
+        }
+        let message = [LINK_CONTEXT, &self.session_hash].concat();
+        if !self.peer_identity.verify(&message, signature) {
+            error!("peer sent an invalid link signature");
+            return Err(CommunicationError::Unauthenticated);
+        }
+        Ok(self.peer_identity.clone())
+    }
+
+    /// Six digit code derived from the keys exchanged in the handshake. Both peers only arrive at
+    /// the same code if nobody has tampered with the handshake, which users can confirm by
+    /// comparing the codes shown on the two devices
+    pub fn verification_code(&self) -> String {
+        let digest = Sha256::new()
+            .chain_update(VERIFICATION_CONTEXT)
+            .chain_update(self.session_hash)
+            .finalize();
+        let code = u32::from_be_bytes(digest[..4].try_into().unwrap()) % 1_000_000;
+        format!("{:03} {:03}", code / 1000, code % 1000)
+    }
+
+    pub fn into_split(self) -> (SecureReader, SecureWriter) {
+        (self.reader, self.writer)
+    }
+}
@@ -1,4 +1,33 @@
-use crate::protocol::TransferType;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use fdrop_config::TransferPolicy;
+use flume::{Receiver, Sender};
+use tokio::{io::AsyncReadExt, sync::oneshot};
+use tracing::warn;
+
+use crate::bandwidth::Throttle;
+use crate::history::{HistoryEntry, TransferKind, TransferStatus};
+use crate::protocol::{
+    FileTransferResponse, ManifestEntry, PrepareDirectoryTransfer, PrepareFileTransfer,
+    TransferType,
+};
+
+static NEXT_TRANSFER_ID: AtomicU32 = AtomicU32::new(1);
+/// Minimum time between two progress reports of a transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Allocate a new id for an outgoing file transfer
+pub(crate) fn next_transfer_id() -> u32 {
+    NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 #[derive(Clone)]
 pub enum DisplayContent {
@@ -18,6 +47,644 @@ pub struct Transfer {
     pub display_content: DisplayContent,
 }
 
+#[derive(Clone, serde::Serialize)]
+pub struct TransferComplete {
+    pub transfer_id: u32,
+    pub file_path: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct TransferFailed {
+    pub transfer_id: u32,
+    pub error: String,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct DirectoryProgress {
+    pub directory_id: u32,
+    pub name: String,
+    pub files_done: u32,
+    pub file_count: u32,
+    pub bytes_done: u64,
+    pub total_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct TransferCancelled {
+    pub transfer_id: u32,
+    pub device: String,
+    pub direction: Direction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStatus {
+    /// Handed over to the connection to the device
+    Sent,
+    /// Acknowledged by the device
+    Delivered,
+    /// The connection was lost before the device acknowledged the message
+    Failed,
+}
+
+/// Change in the status of a text message sent to a device
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct MessageReceipt {
+    pub message_id: u32,
+    pub device: String,
+    pub status: MessageStatus,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct TransferProgress {
+    pub transfer_id: u32,
+    /// Name of the device on the other end of the transfer
+    pub device: String,
+    pub direction: Direction,
+    pub file_name: String,
+    pub bytes_done: u64,
+    pub total_size: u64,
+    /// Bytes per second since the transfer started
+    pub throughput: u64,
+    /// Estimated seconds until the transfer completes. Unknown until some data has been
+    /// transferred
+    pub eta: Option<u64>,
+}
+
+/// Identifies a transfer in progress. Transfer ids are only unique for one device and direction,
+/// hence all three make up the key
+type TransferKey = (String, Direction, u32);
+
+/// Latest progress of every file being sent or received
+#[derive(Clone, Default)]
+pub(crate) struct ActiveTransfers(Arc<std::sync::Mutex<HashMap<TransferKey, TransferProgress>>>);
+
+impl ActiveTransfers {
+    pub fn list(&self) -> Vec<TransferProgress> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+
+    fn update(&self, progress: &TransferProgress) {
+        let key = (
+            progress.device.clone(),
+            progress.direction,
+            progress.transfer_id,
+        );
+        self.0.lock().unwrap().insert(key, progress.clone());
+    }
+
+    fn remove(&self, progress: &TransferProgress) {
+        let key = (
+            progress.device.clone(),
+            progress.direction,
+            progress.transfer_id,
+        );
+        self.0.lock().unwrap().remove(&key);
+    }
+}
+
+/// Tracks the progress of a file transfer and decides when it is reported. The transfer is listed
+/// in [`ActiveTransfers`] until the tracker is dropped
+pub(crate) struct ProgressTracker {
+    progress: TransferProgress,
+    active: ActiveTransfers,
+    started: Instant,
+    /// Bytes that were already transferred when the transfer was resumed
+    resumed_at: u64,
+    last_report: Option<Instant>,
+}
+
+impl ProgressTracker {
+    pub fn new(
+        active: &ActiveTransfers,
+        transfer_id: u32,
+        device: String,
+        direction: Direction,
+        file_name: String,
+        offset: u64,
+        total_size: u64,
+    ) -> Self {
+        let progress = TransferProgress {
+            transfer_id,
+            device,
+            direction,
+            file_name,
+            bytes_done: offset,
+            total_size,
+            throughput: 0,
+            eta: None,
+        };
+        active.update(&progress);
+        Self {
+            progress,
+            active: active.clone(),
+            started: Instant::now(),
+            resumed_at: offset,
+            last_report: None,
+        }
+    }
+
+    /// Record `n` more bytes as transferred. Returns the progress if it is due to be reported,
+    /// which is at most once every [`PROGRESS_INTERVAL`] and always once the transfer is done
+    pub fn advance(&mut self, n: u64) -> Option<TransferProgress> {
+        self.progress.bytes_done += n;
+        let now = Instant::now();
+        let done = self.progress.bytes_done >= self.progress.total_size;
+        if !done
+            && self
+                .last_report
+                .is_some_and(|last| now - last < PROGRESS_INTERVAL)
+        {
+            return None;
+        }
+        self.last_report = Some(now);
+
+        let elapsed = (now - self.started).as_secs_f64();
+        let transferred = self.progress.bytes_done - self.resumed_at;
+        if elapsed > 0.0 && transferred > 0 {
+            let throughput = transferred as f64 / elapsed;
+            let remaining = self
+                .progress
+                .total_size
+                .saturating_sub(self.progress.bytes_done);
+            self.progress.throughput = throughput as u64;
+            self.progress.eta = Some((remaining as f64 / throughput).ceil() as u64);
+        }
+        self.active.update(&self.progress);
+        Some(self.progress.clone())
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        self.active.remove(&self.progress);
+    }
+}
+
+/// A file that is being received from the peer
+pub(crate) struct IncomingFile {
+    pub file: tokio::fs::File,
+    /// Final location of the file once it is fully received
+    pub path: PathBuf,
+    /// Location where the data is written until the transfer completes
+    pub partial_path: PathBuf,
+    pub size: u64,
+    pub received: u64,
+    /// BLAKE3 hash of the whole file as announced by the peer
+    pub hash: Vec<u8>,
+    pub directory_id: Option<u32>,
+    pub assoc_text: Option<String>,
+    pub progress: ProgressTracker,
+}
+
+impl IncomingFile {
+    /// Entry for the file in the history once receiving it ended with `status`
+    pub fn history_entry(&self, device: String, status: TransferStatus) -> HistoryEntry {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        HistoryEntry {
+            assoc_text: self.assoc_text.clone(),
+            path: Some(self.path.clone()),
+            ..HistoryEntry::new(
+                device,
+                Direction::Incoming,
+                TransferKind::File,
+                name.to_string(),
+                self.size,
+                status,
+            )
+        }
+    }
+}
+
+/// A directory that is being received from the peer
+pub(crate) struct IncomingDirectory {
+    pub root: PathBuf,
+    pub assoc_text: Option<String>,
+    pub progress: DirectoryProgress,
+    /// Combined size of the files of the directory accepted so far. Never more than the size the
+    /// peer announced for the directory, which is what the transfer policy was checked against
+    pub accepted_size: u64,
+}
+
+impl IncomingDirectory {
+    /// Entry for the directory in the history once receiving it ended with `status`
+    pub fn history_entry(&self, device: String, status: TransferStatus) -> HistoryEntry {
+        HistoryEntry {
+            assoc_text: self.assoc_text.clone(),
+            path: Some(self.root.clone()),
+            ..HistoryEntry::new(
+                device,
+                Direction::Incoming,
+                TransferKind::Directory,
+                self.progress.name.clone(),
+                self.progress.total_size,
+                status,
+            )
+        }
+    }
+}
+
+/// A file or directory that the peer wants to send, shown to the user for approval
+#[derive(Clone, serde::Serialize)]
+pub struct TransferOffer {
+    pub transfer_id: u32,
+    /// Name of the device offering the transfer
+    pub device: String,
+    pub name: String,
+    pub size: u64,
+    pub directory: bool,
+    pub assoc_text: Option<String>,
+}
+
+/// A transfer offered by the peer that is waiting for the user to answer
+pub(crate) enum Offer {
+    File(PrepareFileTransfer),
+    Directory(PrepareDirectoryTransfer),
+}
+
+impl Offer {
+    /// Describe the transfer offered by the device `device`
+    pub fn describe(&self, device: String) -> TransferOffer {
+        match self {
+            Offer::File(message) => TransferOffer {
+                transfer_id: message.transfer_id,
+                device,
+                name: message.file_name.clone(),
+                size: message.size,
+                directory: false,
+                assoc_text: message.assoc_text.clone(),
+            },
+            Offer::Directory(message) => TransferOffer {
+                transfer_id: message.directory_id,
+                device,
+                name: message.name.clone(),
+                size: message.total_size,
+                directory: true,
+                assoc_text: message.assoc_text.clone(),
+            },
+        }
+    }
+}
+
+/// Answer to a transfer offer as given by the policy of the device
+#[derive(Debug, PartialEq)]
+pub(crate) enum PolicyDecision {
+    Accept,
+    Decline(String),
+    Ask,
+}
+
+/// Decide on an offer of `size` bytes without involving the user, if `policy` allows
+pub(crate) fn policy_decision(policy: &TransferPolicy, size: u64) -> PolicyDecision {
+    match policy.decline_above {
+        Some(limit) if size > limit => {
+            PolicyDecision::Decline(format!("transfer is larger than {limit} bytes"))
+        }
+        _ if policy.auto_accept => PolicyDecision::Accept,
+        _ => PolicyDecision::Ask,
+    }
+}
+
+/// Transfers being received on a stream, keyed by the ids chosen by the peer
+pub(crate) struct Incoming {
+    /// Hex encoded identity key of the peer
+    pub peer_key: String,
+    pub peer_name: String,
+    /// Hash of the handshake of the stream, signed when answering a `Link` from the peer
+    pub session_hash: [u8; 32],
+    pub files: HashMap<u32, IncomingFile>,
+    pub directories: HashMap<u32, IncomingDirectory>,
+    pub offers: HashMap<u32, Offer>,
+    /// Where the answers of the user to `offers` are sent
+    pub control: Sender<Control>,
+}
+
+impl Incoming {
+    pub fn new(
+        peer_key: String,
+        peer_name: String,
+        session_hash: [u8; 32],
+        control: Sender<Control>,
+    ) -> Self {
+        Self {
+            peer_key,
+            peer_name,
+            session_hash,
+            files: HashMap::new(),
+            directories: HashMap::new(),
+            offers: HashMap::new(),
+            control,
+        }
+    }
+}
+
+/// Folder in the data folder where files are kept while they are received. Files only enter the
+/// FDrop folder once their checksum has been verified
+const PARTIAL_DIR: &str = "partial";
+
+/// Path where the partially received contents of the file at `path` are kept.
+///
+/// The destination and the size are made part of the name so that a different file which happens
+/// to have the same name does not get resumed from an unrelated partial file.
+pub(crate) fn partial_path(data_dir: &Path, path: &Path, size: u64) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let destination = blake3::hash(path.as_os_str().as_encoded_bytes()).to_hex();
+    data_dir.join(PARTIAL_DIR).join(format!(
+        "{}-{file_name}.{size}.fdrop-part",
+        &destination[..16]
+    ))
+}
+
+/// Move a received file from the partial folder to its destination, which may be on another file
+/// system
+pub(crate) async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+/// Convert a '/' separated path sent by the peer into a relative path. Returns `None` if the path
+/// would point outside the directory it is relative to
+pub(crate) fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) => relative.push(c),
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// Walk the directory tree at `root` and list every file and subdirectory in it relative to
+/// `root`. Symlinks and entries with names that are not valid UTF-8 are skipped
+pub(crate) async fn walk_directory(root: &Path) -> std::io::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = stack.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(|n| n.to_string()) else {
+                warn!(path = ?entry.path(), "skipping file with invalid name");
+                continue;
+            };
+            let path = prefix.clone() + &name;
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                stack.push((entry.path(), path.clone() + "/"));
+                entries.push(ManifestEntry {
+                    path,
+                    size: 0,
+                    directory: true,
+                });
+            } else if file_type.is_file() {
+                entries.push(ManifestEntry {
+                    path,
+                    size: entry.metadata().await?.len(),
+                    directory: false,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Compute the BLAKE3 hash of the file at `path`
+pub(crate) async fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Requests to the task serving the stream of a connection
+#[derive(Debug)]
+pub(crate) enum Control {
+    /// The user answered the transfer offer `transfer_id`
+    Decision { transfer_id: u32, accepted: bool },
+    /// Stop receiving the transfer `transfer_id`. `found` is told whether the transfer existed
+    CancelIncoming {
+        transfer_id: u32,
+        found: oneshot::Sender<bool>,
+    },
+    /// Tell the peer that the link ended and close the stream. `done` is notified once the peer
+    /// was told
+    Unlink { done: oneshot::Sender<()> },
+}
+
+/// Where an outgoing transfer stands, as far as the task sending it is concerned
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) enum OutgoingState {
+    #[default]
+    Sending,
+    Cancelled,
+    /// The peer failed to receive the transfer and threw it away
+    Failed(String),
+}
+
+/// Transfers of a connection, shared between the task serving its stream and the rest of the core
+#[derive(Clone, Debug)]
+pub(crate) struct PendingTransfers {
+    /// Outgoing transfers waiting for the peer to accept or decline them
+    responses: Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<FileTransferResponse>>>>,
+    /// Outgoing transfers that were sent in full, waiting for the peer to confirm it stored them
+    confirmations: Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+    /// Outgoing transfers that are registered, mapped to where they stand
+    outgoing: Arc<std::sync::Mutex<HashMap<u32, OutgoingState>>>,
+    /// Text messages that the peer has not acknowledged yet
+    unacked: Arc<std::sync::Mutex<HashSet<u32>>>,
+    control: Sender<Control>,
+    control_rx: Receiver<Control>,
+    /// Limits the upload rate to the device
+    pub throttle: Throttle,
+}
+
+impl Default for PendingTransfers {
+    fn default() -> Self {
+        let (control, control_rx) = flume::unbounded();
+        Self {
+            responses: Arc::default(),
+            confirmations: Arc::default(),
+            outgoing: Arc::default(),
+            unacked: Arc::default(),
+            control,
+            control_rx,
+            throttle: Throttle::default(),
+        }
+    }
+}
+
+impl PendingTransfers {
+    pub fn register(&self, transfer_id: u32) -> OutgoingTransfer {
+        let (tx, rx) = oneshot::channel();
+        self.responses.lock().unwrap().insert(transfer_id, tx);
+        let (confirm_tx, confirm_rx) = oneshot::channel();
+        self.confirmations
+            .lock()
+            .unwrap()
+            .insert(transfer_id, confirm_tx);
+        self.outgoing
+            .lock()
+            .unwrap()
+            .insert(transfer_id, OutgoingState::Sending);
+        OutgoingTransfer {
+            transfer_id,
+            pending: self.clone(),
+            response: rx,
+            confirmation: confirm_rx,
+        }
+    }
+
+    /// Hand over the answer of the peer to the task sending the transfer. Returns `false` if no
+    /// transfer with the id in `response` is waiting
+    pub fn resolve(&self, response: FileTransferResponse) -> bool {
+        match self.responses.lock().unwrap().remove(&response.transfer_id) {
+            Some(tx) => tx.send(response).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Tell the task sending the transfer `transfer_id` that the peer stored it. Returns `false`
+    /// if no such transfer is waiting
+    pub fn confirm(&self, transfer_id: u32) -> bool {
+        match self.confirmations.lock().unwrap().remove(&transfer_id) {
+            Some(tx) => tx.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Mark the outgoing transfer `transfer_id` as cancelled. Returns `false` if no such
+    /// transfer is registered
+    pub fn cancel(&self, transfer_id: u32) -> bool {
+        self.set_state(transfer_id, OutgoingState::Cancelled)
+    }
+
+    /// Mark the outgoing transfer `transfer_id` as failed on the side of the peer. Returns
+    /// `false` if no such transfer is registered
+    pub fn fail(&self, transfer_id: u32, reason: String) -> bool {
+        self.set_state(transfer_id, OutgoingState::Failed(reason))
+    }
+
+    fn set_state(&self, transfer_id: u32, state: OutgoingState) -> bool {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let Some(current) = outgoing.get_mut(&transfer_id) else {
+            return false;
+        };
+        *current = state;
+        // Wakes up the task if it is still waiting for the peer to answer
+        self.responses.lock().unwrap().remove(&transfer_id);
+        self.confirmations.lock().unwrap().remove(&transfer_id);
+        true
+    }
+
+    /// Stop waiting for the peer to answer the outgoing transfers, for example because the stream
+    /// to it is gone. The tasks waiting for an answer are woken up
+    pub fn abandon(&self) {
+        self.responses.lock().unwrap().clear();
+        self.confirmations.lock().unwrap().clear();
+    }
+
+    /// Wait for the peer to acknowledge the text message `message_id`. Must be called before
+    /// the message is sent, as the acknowledgement may arrive right after
+    pub fn expect_ack(&self, message_id: u32) {
+        self.unacked.lock().unwrap().insert(message_id);
+    }
+
+    /// Returns `false` if the text message `message_id` was not waiting to be acknowledged
+    pub fn acknowledge(&self, message_id: u32) -> bool {
+        self.unacked.lock().unwrap().remove(&message_id)
+    }
+
+    /// Stop waiting for acknowledgements and return the text messages that did not get one
+    pub fn take_unacked(&self) -> Vec<u32> {
+        self.unacked.lock().unwrap().drain().collect()
+    }
+
+    pub fn is_cancelled(&self, transfer_id: u32) -> bool {
+        self.state(transfer_id) == OutgoingState::Cancelled
+    }
+
+    fn state(&self, transfer_id: u32) -> OutgoingState {
+        self.outgoing
+            .lock()
+            .unwrap()
+            .get(&transfer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn control(&self) -> Sender<Control> {
+        self.control.clone()
+    }
+
+    pub fn control_rx(&self) -> Receiver<Control> {
+        self.control_rx.clone()
+    }
+}
+
+/// An outgoing transfer registered with [`PendingTransfers`]. It is forgotten once dropped
+pub(crate) struct OutgoingTransfer {
+    transfer_id: u32,
+    pending: PendingTransfers,
+    response: oneshot::Receiver<FileTransferResponse>,
+    confirmation: oneshot::Receiver<()>,
+}
+
+impl OutgoingTransfer {
+    /// Wait for the peer to accept or decline the transfer. Returns `None` if the transfer was
+    /// cancelled before that
+    pub async fn response(&mut self) -> Option<FileTransferResponse> {
+        (&mut self.response).await.ok()
+    }
+
+    /// Wait for the peer to confirm that it stored the transfer. Returns `false` if the transfer
+    /// stopped before that
+    pub async fn confirmation(&mut self) -> bool {
+        (&mut self.confirmation).await.is_ok()
+    }
+
+    pub fn state(&self) -> OutgoingState {
+        self.pending.state(self.transfer_id)
+    }
+}
+
+impl Drop for OutgoingTransfer {
+    fn drop(&mut self) {
+        self.pending
+            .responses
+            .lock()
+            .unwrap()
+            .remove(&self.transfer_id);
+        self.pending
+            .confirmations
+            .lock()
+            .unwrap()
+            .remove(&self.transfer_id);
+        self.pending
+            .outgoing
+            .lock()
+            .unwrap()
+            .remove(&self.transfer_id);
+    }
+}
+
 impl serde::Serialize for DisplayContent {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -1,5 +1,5 @@
 use fdrop_net::{ConnectionInfo, ConnectionManager};
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 use tauri::{AppHandle, Manager, WebviewUrl, WindowEvent};
 use tokio::sync::Mutex;
 use tracing::info;
@@ -28,26 +28,33 @@ pub fn run() {
             fdrop_config::commands::check_first_launch,
             fdrop_config::commands::initial_setup,
             fdrop_config::commands::generate_keys,
+            fdrop_config::commands::list_trusted_devices,
+            fdrop_config::commands::set_transfer_policy,
             open_link_device_window,
             get_available_connections,
             fdrop_net::commands::enable_networking,
             fdrop_net::commands::send_text_message,
             fdrop_net::commands::link_device_by_name,
+            fdrop_net::commands::unlink_device,
+            fdrop_net::commands::remove_trusted_device,
             fdrop_net::commands::send_files,
+            fdrop_net::commands::send_directory,
+            fdrop_net::commands::add_peer,
+            fdrop_net::commands::active_transfers,
+            fdrop_net::commands::cancel_transfer,
+            fdrop_net::commands::set_upload_limit,
+            fdrop_net::commands::transfer_history,
+            fdrop_net::commands::queued_items,
         ])
         .setup(|app| {
             let connection_manager = fdrop_net::ConnectionManager::new()?;
-            app.manage(connection_manager);
+            app.manage(Arc::new(connection_manager));
 
-            if !tauri::async_runtime::block_on(fdrop_config::check_first_launch(&app.handle())) {
-                let user_config = Mutex::new(fdrop_config::get_details_from_config(&app.handle())?);
-                app.manage(user_config);
-            }
             let main_window = app.get_webview_window("main").unwrap();
             let main_window2 = main_window.clone();
             main_window.on_window_event(move |event| {
                 if matches!(event, WindowEvent::CloseRequested { .. }) {
-                    let cm_lock = main_window2.state::<Mutex<ConnectionManager>>();
+                    let cm_lock = main_window2.state::<Arc<Mutex<ConnectionManager>>>();
                     let connection_manager = tauri::async_runtime::block_on(cm_lock.lock());
                     connection_manager.shutdown().unwrap();
                     info!("shutdown mdns daemon");
@@ -61,7 +68,7 @@ pub fn run() {
 
 #[tauri::command]
 async fn get_available_connections(handle: AppHandle) -> String {
-    let cm_lock = handle.state::<Mutex<ConnectionManager>>();
+    let cm_lock = handle.state::<Arc<Mutex<ConnectionManager>>>();
     let connection_manager = cm_lock.lock().await;
     serde_json::to_string(
         &connection_manager
@@ -5,9 +5,17 @@ import { SvelteMap } from "svelte/reactivity";
 export type ConnectionInfo = {
   name: string,
   linked: boolean
+  state: ConnectionState,
   platform?: string,
 }
 
+export type ConnectionState = "offline" | "connecting" | "linked";
+
+export type VerificationCode = {
+  name: string,
+  code: string,
+}
+
 export enum Sender {
   Local,
   Peer,
@@ -16,6 +24,7 @@ export enum Sender {
 export enum TransferType {
   TextMessage,
   PrepareFileTransfer,
+  PrepareDirectoryTransfer,
 }
 
 export function transferTypeFromString(s: string): TransferType {
@@ -24,6 +33,8 @@ export function transferTypeFromString(s: string): TransferType {
       return TransferType.TextMessage;
     case "PrepareFileTransfer":
       return TransferType.PrepareFileTransfer;
+    case "PrepareDirectoryTransfer":
+      return TransferType.PrepareDirectoryTransfer;
   }
 }
 
@@ -32,10 +43,173 @@ export type DisplayFileTransfer = {
   file_path: string[]
 }
 
+export type DirectoryProgress = {
+  directory_id: number,
+  name: string,
+  files_done: number,
+  file_count: number,
+  bytes_done: number,
+  total_size: number,
+}
+
+export type TransferProgress = {
+  transfer_id: number,
+  device: string,
+  direction: "incoming" | "outgoing",
+  file_name: string,
+  bytes_done: number,
+  total_size: number,
+  throughput: number,
+  eta?: number | null,
+}
+
 export type Transfer = {
   ttype: TransferType,
   display_content: string | DisplayFileTransfer,
   sentby: Sender | undefined,
+  // Id of a text message sent in this session, under which its receipts are reported
+  message_id?: number,
+}
+
+export type MessageStatus = "sent" | "delivered" | "failed";
+
+export type MessageReceipt = {
+  message_id: number,
+  device: string,
+  status: MessageStatus,
+}
+
+export type TransferPolicy = {
+  auto_accept: boolean,
+  decline_above?: number | null,
+}
+
+export type TrustedDevice = {
+  public_key: string,
+  name: string,
+  platform?: string | null,
+  first_seen: number,
+  last_seen: number,
+  policy: TransferPolicy,
+  upload_limit?: number | null,
+}
+
+export type TransferOffer = {
+  transfer_id: number,
+  device: string,
+  name: string,
+  size: number,
+  directory: boolean,
+  assoc_text?: string | null,
+}
+
+/* Latest progress of every file being sent or received. Updates are reported through the
+ * `transfer-progress` event
+ */
+export async function active_transfers(): Promise<TransferProgress[]> {
+  return await invoke("active_transfers");
+}
+
+export type TransferCancelled = {
+  transfer_id: number,
+  device: string,
+  direction: "incoming" | "outgoing",
+}
+
+/* Cancel a transfer to or from `cname`. Both sides get a `transfer-cancelled` event */
+export async function cancel_transfer(
+  cname: string,
+  transfer_id: number,
+  direction: "incoming" | "outgoing",
+) {
+  await invoke("cancel_transfer", { cname, transferId: transfer_id, direction });
+}
+
+/* Limit the upload rate in bytes per second to `cname`, or to all devices together when `cname`
+ * is null. A null limit removes the limit
+ */
+export async function set_upload_limit(cname: string | null, limit: number | null) {
+  await invoke("set_upload_limit", { cname, limit });
+}
+
+export type HistoryEntry = {
+  device: string,
+  direction: "incoming" | "outgoing",
+  kind: "text" | "file" | "directory",
+  timestamp: number,
+  content: string,
+  assoc_text?: string | null,
+  size: number,
+  status: "completed" | "failed" | "cancelled" | "declined",
+  path?: string | null,
+}
+
+/* Transfers exchanged with `cname`, most recent first. Skips `offset` entries and returns at most
+ * `limit`
+ */
+export async function transfer_history(
+  cname: string,
+  offset: number,
+  limit: number,
+): Promise<HistoryEntry[]> {
+  return await invoke("transfer_history", { cname, offset, limit });
+}
+
+export type QueuedContent =
+  | { kind: "text", contents: string }
+  | { kind: "files", paths: string[], assoc_text?: string | null }
+  | { kind: "directory", path: string, assoc_text?: string | null };
+
+export type QueuedItem = {
+  id: number,
+  device: string,
+  timestamp: number,
+  content: QueuedContent,
+}
+
+export type Delivery = {
+  id: number,
+  device: string,
+  state: "queued" | "sending" | "delivered" | "failed",
+  error?: string | null,
+}
+
+/* Text messages and file offers waiting for `cname` to be linked again, oldest first */
+export async function queued_items(cname: string): Promise<QueuedItem[]> {
+  return await invoke("queued_items", { cname });
+}
+
+/* Show an entry of the history the way a transfer of this session is shown */
+export function transferFromHistory(entry: HistoryEntry): Transfer {
+  const sentby = entry.direction == "outgoing" ? Sender.Local : Sender.Peer;
+  if (entry.kind == "text")
+    return { ttype: TransferType.TextMessage, display_content: entry.content, sentby };
+  return {
+    ttype:
+      entry.kind == "file"
+        ? TransferType.PrepareFileTransfer
+        : TransferType.PrepareDirectoryTransfer,
+    display_content: {
+      assoc_text: entry.assoc_text,
+      file_path: entry.path ?? entry.content,
+    },
+    sentby,
+  };
+}
+
+export async function list_trusted_devices(): Promise<TrustedDevice[]> {
+  return await invoke("list_trusted_devices");
+}
+
+export async function remove_trusted_device(public_key: string): Promise<boolean> {
+  return await invoke("remove_trusted_device", { publicKey: public_key });
+}
+
+export async function set_transfer_policy(
+  public_key: string,
+  policy: TransferPolicy,
+): Promise<boolean> {
+  return await invoke("set_transfer_policy", { publicKey: public_key, policy });
 }
 
 export function realname(conn: ConnectionInfo): string {
@@ -57,12 +231,52 @@ export function listen_device_events() {
   listen<ConnectionInfo>("device-removed", (event) => {
     available_devices.delete(event.payload.name);
   });
+  listen<ConnectionInfo>("device-connecting", (event) => {
+    let device = available_devices.get(event.payload.name);
+    if (device) {
+      device.state = event.payload.state;
+      available_devices.set(event.payload.name, device);
+    }
+  });
   listen<ConnectionInfo>("device-linked", (event) => {
     let device = available_devices.get(event.payload.name);
     device!.linked = true;
+    device!.state = event.payload.state;
     device!.platform = event.payload.platform;
     available_devices.set(event.payload.name, device!);
   });
+  listen<ConnectionInfo>("device-unlinked", (event) => {
+    let device = available_devices.get(event.payload.name);
+    if (device) {
+      device.linked = false;
+      device.state = event.payload.state;
+      available_devices.set(event.payload.name, device);
+    }
+  });
+  listen<ConnectionInfo>("device-disconnected", (event) => {
+    let device = available_devices.get(event.payload.name);
+    if (device) {
+      device.linked = false;
+      device.state = event.payload.state;
+      available_devices.set(event.payload.name, device);
+    }
+  });
+}
+
+/* End the link with `cname`. The device is told so and no longer trusted on either side */
+export async function unlink_device(cname: string) {
+  await invoke("unlink_device", { cname });
+}
+
+/* Add a device by its address, for networks where it cannot be discovered. The device shows up
+ * through the usual `device-discovered` event
+ */
+export async function add_peer(
+  host: string,
+  port: number | null,
+  remember: boolean,
+): Promise<ConnectionInfo> {
+  return await invoke("add_peer", { host, port, remember });
 }
 
 export function enable_networking() {
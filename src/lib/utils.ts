@@ -2,3 +2,13 @@ export function filename(s: string): string {
   let last_fwdslash = s.lastIndexOf("/") + 1;
   return s.slice(last_fwdslash, s.length);
 }
+
+export function human_size(bytes: number): string {
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return `${i == 0 ? bytes : bytes.toFixed(1)} ${units[i]}`;
+}
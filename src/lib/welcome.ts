@@ -5,6 +5,15 @@ export type UserConfig = {
   user: string;
   instance_name: string;
   fdrop_dir: string;
+  port?: number;
+  bind_address?: string | null;
+  upload_limit?: number | null;
+  blocked?: BlockedDevice[];
+};
+
+export type BlockedDevice = {
+  name: string;
+  public_key: string | null;
 };
 
 type Page = {